@@ -0,0 +1,86 @@
+use crate::lists::DomainMatch;
+use crate::{Check, CheckVerdict};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Wording of [`Check::explain`], Russian or English.
+struct Phrases {
+    domain: fn(&str) -> String,
+    parent_domain: fn(&str) -> String,
+    providers: fn(&str) -> String,
+    subnet: fn(&IpAddr, &IpNet) -> String,
+    blocked: &'static str,
+    clear: &'static str,
+    clear_but: &'static str,
+    no_guarantee: &'static str,
+}
+
+const RU: Phrases = Phrases {
+    domain: |domain| format!("домен {domain} внесён в реестр РКН"),
+    parent_domain: |domain| format!("родительский домен {domain} внесён в реестр РКН"),
+    providers: |providers| format!("адреса принадлежат диапазонам {providers}"),
+    subnet: |ip, net| format!("IP {ip} входит в заблокированную подсеть {net}"),
+    blocked: "ресурс найден в списках блокировок",
+    clear: "ограничений не обнаружено",
+    clear_but: "ограничений не обнаружено, но",
+    no_guarantee: "(не гарантирует блокировку)",
+};
+
+const EN: Phrases = Phrases {
+    domain: |domain| format!("domain {domain} is listed in the RKN registry"),
+    parent_domain: |domain| format!("parent domain {domain} is listed in the RKN registry"),
+    providers: |providers| format!("addresses belong to {providers} ranges"),
+    subnet: |ip, net| format!("IP {ip} is in blocked subnet {net}"),
+    blocked: "the resource was found in the block lists",
+    clear: "no restrictions found",
+    clear_but: "no restrictions found, but",
+    no_guarantee: "(doesn't guarantee a block)",
+};
+
+impl Check {
+    /// Human-readable reason for the verdict in `lang` (`ru`, anything else is English),
+    /// e.g. "Domain example.com is listed in the RKN registry; addresses belong to Cloudflare ranges".
+    /// Blocked subnets of a clear target are mentioned as not guaranteeing a block.
+    pub fn explain(&self, lang: &str) -> String {
+        let phrases = if lang.eq_ignore_ascii_case("ru") { &RU } else { &EN };
+        let subnets: Vec<String> = self.ips.iter()
+            .filter_map(|ip| self.rkn_subnets.iter()
+                .filter(|net| net.contains(ip))
+                .max_by_key(|net| net.prefix_len())
+                .map(|net| (phrases.subnet)(ip, net)))
+            .collect();
+
+        let explanation = match &self.verdict {
+            CheckVerdict::Clear if subnets.is_empty() => phrases.clear.to_string(),
+            CheckVerdict::Clear => format!("{} {} {}", phrases.clear_but, subnets.join("; "), phrases.no_guarantee),
+            CheckVerdict::Blocked { rkn_domain, cdn_provider_subnets } => {
+                let mut reasons = vec![];
+                match rkn_domain {
+                    Some(DomainMatch::Exact(domain)) => reasons.push((phrases.domain)(domain)),
+                    Some(DomainMatch::Parent(domain)) => reasons.push((phrases.parent_domain)(domain)),
+                    None => {}
+                }
+                if !cdn_provider_subnets.is_empty() {
+                    let mut providers: Vec<&str> = cdn_provider_subnets.keys().map(String::as_str).collect();
+                    providers.sort();
+                    reasons.push((phrases.providers)(&providers.join(", ")));
+                }
+                reasons.extend(subnets);
+                if reasons.is_empty() {
+                    phrases.blocked.to_string()
+                } else {
+                    reasons.join("; ")
+                }
+            }
+        };
+        capitalize(&explanation)
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,239 @@
+use crate::updater::Progress;
+use flate2::read::MultiGzDecoder;
+use futures_util::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{IntoUrl, RequestBuilder, Response, StatusCode};
+use std::fmt::Display;
+use std::io;
+use std::io::{Error, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+/// On-disk copy of a downloaded database in `DB_CACHE_DIR`,
+/// revalidated with its `ETag` / `Last-Modified` on the next fetch.
+struct CachedDb {
+    body: PathBuf,
+    meta: PathBuf,
+}
+
+impl CachedDb {
+    fn for_url(url: &Url) -> Option<CachedDb> {
+        let dir = PathBuf::from(std::env::var("DB_CACHE_DIR").ok()?);
+        let name: String = url.as_str().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        Some(CachedDb {
+            body: dir.join(format!("{name}.bin")),
+            meta: dir.join(format!("{name}.meta")),
+        })
+    }
+
+    /// `ETag` and `Last-Modified` of the cached copy, empty when the server sent none.
+    async fn validators(&self) -> Option<(String, String)> {
+        if !tokio::fs::try_exists(&self.body).await.unwrap_or(false) {
+            return None;
+        }
+        let meta = tokio::fs::read_to_string(&self.meta).await.ok()?;
+        let mut lines = meta.lines();
+        Some((lines.next().unwrap_or_default().to_string(), lines.next().unwrap_or_default().to_string()))
+    }
+
+    async fn store(&self, etag: &str, last_modified: &str, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(dir) = self.body.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&self.body, bytes).await?;
+        tokio::fs::write(&self.meta, format!("{etag}\n{last_modified}\n")).await
+    }
+}
+
+/// Downloads `url`, or reads it from disk for `file://` URLs.
+/// With `DB_CACHE_DIR` set, unchanged databases are served from the cache.
+/// Gzip-compressed databases are decompressed, so callers always get plaintext.
+pub async fn fetch_db<T: IntoUrl + Display>(url: T, progress: &Progress) -> Result<Vec<u8>, Error> {
+    info!("Fetching {}", url);
+    gunzip(fetch_raw(url, progress).await?)
+}
+
+/// Decompresses `bytes` if they start with the gzip magic, whatever the URL
+/// or `Content-Encoding` claimed.
+fn gunzip(bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(bytes);
+    }
+    let mut plain = Vec::new();
+    MultiGzDecoder::new(bytes.as_slice()).read_to_end(&mut plain)
+        .map_err(|e| Error::new(e.kind(), format!("gzip: {}", e)))?;
+    info!("Decompressed {} bytes to {}", bytes.len(), plain.len());
+    Ok(plain)
+}
+
+async fn fetch_raw<T: IntoUrl + Display>(url: T, progress: &Progress) -> Result<Vec<u8>, Error> {
+    // reqwest rejects URLs without a host, so file:// has to be told apart first
+    let url = Url::parse(&url.to_string())
+        .map_err(|e| Error::new(io::ErrorKind::InvalidInput, e))?;
+    if url.scheme() == "file" {
+        let path = url.to_file_path()
+            .map_err(|_| Error::new(io::ErrorKind::InvalidInput, format!("invalid file URL: {}", url)))?;
+        let bytes = tokio::fs::read(&path).await
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        progress.advance(bytes.len() as u64, bytes.len() as u64);
+        return Ok(bytes);
+    }
+
+    let cache = CachedDb::for_url(&url);
+    let mut request = reqwest::Client::new().get(url.clone());
+    if let Some(cache) = &cache && let Some((etag, last_modified)) = cache.validators().await {
+        if !etag.is_empty() {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if !last_modified.is_empty() {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = send_with_retry(request, &url).await?;
+    if let (StatusCode::NOT_MODIFIED, Some(cache)) = (response.status(), &cache) {
+        info!("{} not modified, using cached copy", url);
+        let bytes = tokio::fs::read(&cache.body).await?;
+        progress.advance(bytes.len() as u64, bytes.len() as u64);
+        return Ok(bytes);
+    }
+    let response = response.error_for_status()
+        .map_err(|e| Error::new(io::ErrorKind::Other, e))?;
+
+    let header = |name| response.headers().get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+
+    let total_size = response.content_length().unwrap_or(0);
+    progress.advance(0, total_size);
+    let pb = ProgressBar::new(total_size);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .map_err(|e| Error::new(io::ErrorKind::Other, e))?
+        .progress_chars("#>-"));
+
+    let mut bytes = Vec::new();
+    bytes.reserve(total_size as usize);
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk_result) = stream.next().await {
+        let chunk = chunk_result.map_err(|e| Error::new(io::ErrorKind::Other, e))?;
+        bytes.extend(&chunk);
+        pb.inc(chunk.len() as u64);
+        progress.advance(chunk.len() as u64, 0);
+    }
+
+    pb.finish_with_message("Download complete!");
+
+    if let Some(cache) = &cache && let Err(e) = cache.store(&etag, &last_modified, &bytes).await {
+        warn!("Failed to cache {}: {}", url, e);
+    }
+
+    Ok(bytes)
+}
+
+/// Sends `request`, retrying connection failures, timeouts and 5xx responses
+/// `FETCH_ATTEMPTS` times in total, doubling a `FETCH_BACKOFF_MS` delay between them.
+async fn send_with_retry(request: RequestBuilder, url: &Url) -> Result<Response, Error> {
+    let attempts: u32 = std::env::var("FETCH_ATTEMPTS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3)
+        .max(1);
+    let backoff = Duration::from_millis(std::env::var("FETCH_BACKOFF_MS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000));
+
+    let mut attempt = 1;
+    loop {
+        let result = request.try_clone()
+            .expect("GET requests have no streaming body")
+            .send().await;
+        let failure = match &result {
+            Ok(response) if response.status().is_server_error() => Some(response.status().to_string()),
+            Err(e) if e.is_connect() || e.is_timeout() => Some(e.to_string()),
+            _ => None,
+        };
+        match failure {
+            Some(failure) if attempt < attempts => {
+                let delay = backoff.saturating_mul(2u32.saturating_pow(attempt - 1));
+                warn!("Fetching {} failed ({}), retrying in {:?} ({}/{})", url, failure, delay, attempt, attempts);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return result.map_err(|e| Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::NetListFormat;
+    use crate::updater::UpdateStatus;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use ipnet::IpNet;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    const LIST: &str = "10.0.0.0/8\n192.0.2.1/32\n";
+
+    fn gzipped() -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(LIST.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn progress() -> Progress {
+        Progress::new(watch::Sender::new(UpdateStatus::default()), |s| &mut s.rkn)
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<IpNet> {
+        std::str::from_utf8(bytes).unwrap().lines()
+            .flat_map(|line| NetListFormat::Lst.parse_line(line).unwrap())
+            .collect()
+    }
+
+    fn expected() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "192.0.2.1/32".parse().unwrap()]
+    }
+
+    #[tokio::test]
+    async fn gz_suffix() {
+        let path = std::env::temp_dir().join(format!("cheburcheck-{}-list.lst.gz", std::process::id()));
+        std::fs::write(&path, gzipped()).unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+        let bytes = fetch_db(url.as_str(), &progress()).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(parse(&bytes.unwrap()), expected());
+    }
+
+    #[tokio::test]
+    async fn content_encoding() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let body = gzipped();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        });
+        let bytes = fetch_db(format!("http://{addr}/list"), &progress()).await.unwrap();
+        assert_eq!(parse(&bytes), expected());
+    }
+}
@@ -1,24 +1,42 @@
-use crate::updater::{fetch_db, Updatable};
+use crate::updater::{Updatable, UpdateError};
+#[cfg(feature = "network")]
+use crate::updater::{fetch_db, Progress};
+#[cfg(feature = "network")]
+use log::info;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use maxminddb::geoip2::{city, country, City, Country};
 use maxminddb::{geoip2, MaxMindDbError};
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::io::Error;
 use std::net::IpAddr;
 use std::io;
+use std::path::Path;
 
 pub struct GeoIp {
     asn: Option<maxminddb::Reader<Vec<u8>>>,
     city: Option<maxminddb::Reader<Vec<u8>>>,
     country: Option<maxminddb::Reader<Vec<u8>>>,
+    /// When a database was last swapped in
+    pub installed_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct IpInfo {
+    /// Display form, e.g. `AS12345`
     pub asn: Option<String>,
+    pub asn_number: Option<u32>,
     pub country_code: Option<String>,
+    /// English country name
+    pub country_name: Option<String>,
+    /// English city name
+    pub city_name: Option<String>,
     pub organisation: Option<String>,
     pub city_geo_name_id: Option<u32>,
+    /// Approximate coordinates from the City database, absent for records without a location
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
     location: String,
 }
 
@@ -26,9 +44,14 @@ impl Default for IpInfo {
     fn default() -> IpInfo {
         IpInfo {
             asn: None,
+            asn_number: None,
             country_code: None,
+            country_name: None,
+            city_name: None,
             organisation: None,
             city_geo_name_id: None,
+            latitude: None,
+            longitude: None,
             location: "-".to_string(),
         }
     }
@@ -39,18 +62,97 @@ impl GeoIp {
         GeoIp {
             asn: None,
             country: None,
-            city: None
+            city: None,
+            installed_at: None,
         }
     }
 
+    /// Loads databases from local mmdb files instead of downloading them.
+    pub fn from_paths(asn: &Path, country: &Path, city: &Path) -> Result<Self, Error> {
+        let mut geo_ip = GeoIp::new();
+        geo_ip.update(Self::read_db(asn)?, Self::read_db(country)?, Self::read_db(city)?)
+            .map_err(|e| Error::new(io::ErrorKind::Other, e))?;
+        Ok(geo_ip)
+    }
+
+    fn read_db(path: &Path) -> Result<Vec<u8>, Error> {
+        std::fs::read(path)
+            .map_err(|e| Error::new(e.kind(), format!("GeoIP database {}: {}", path.display(), e)))
+    }
+
+    /// Reads the database from the file in `path_key` if set, otherwise fetches it.
+    /// Setting `url_key` to an empty string skips the database altogether.
+    #[cfg(feature = "network")]
+    async fn load_db(path_key: &str, url_key: &'static str, default: &'static str, progress: &Progress) -> Result<Option<Vec<u8>>, Error> {
+        if let Ok(path) = std::env::var(path_key) {
+            return Self::read_db(Path::new(&path)).map(Some);
+        }
+        let url = Self::get_url(url_key, default);
+        if url.is_empty() {
+            info!("{} is empty, skipping", url_key);
+            return Ok(None);
+        }
+        fetch_db(url, progress).await.map(Some)
+    }
+
+    fn localized<'a>(names: &BTreeMap<&'a str, &'a str>, lang: &str) -> Option<&'a str> {
+        names.get(lang)
+            .or_else(|| names.get("en"))
+            .or_else(|| names.values().next())
+            .copied()
+    }
+
     pub fn update(&mut self, asn: Vec<u8>, country: Vec<u8>, city: Vec<u8>) -> Result<(), MaxMindDbError>  {
+        self.install_asn(asn)?;
+        self.install_country(country)?;
+        self.install_city(city)
+    }
+
+    pub fn install_asn(&mut self, asn: Vec<u8>) -> Result<(), MaxMindDbError> {
         self.asn = Some(maxminddb::Reader::from_source(asn)?);
+        self.installed_at = Some(Utc::now());
+        Ok(())
+    }
+
+    pub fn install_country(&mut self, country: Vec<u8>) -> Result<(), MaxMindDbError> {
         self.country = Some(maxminddb::Reader::from_source(country)?);
+        self.installed_at = Some(Utc::now());
+        Ok(())
+    }
+
+    /// The city database is the largest one; without it `IpInfo` has no city fields.
+    pub fn install_city(&mut self, city: Vec<u8>) -> Result<(), MaxMindDbError> {
         self.city = Some(maxminddb::Reader::from_source(city)?);
+        self.installed_at = Some(Utc::now());
         Ok(())
     }
 
+    /// Search tree nodes across the installed databases, 0 when none is loaded.
+    pub fn node_count(&self) -> u64 {
+        [&self.asn, &self.country, &self.city].into_iter().flatten()
+            .map(|reader| reader.metadata.node_count as u64)
+            .sum()
+    }
+
+    /// Only the AS number of `ip`, skipping the country and city databases.
+    pub fn asn(&self, ip: IpAddr) -> Result<Option<u32>, MaxMindDbError> {
+        let Some(db) = &self.asn else {
+            return Ok(None);
+        };
+        Ok(db.lookup::<geoip2::Asn>(ip)?.and_then(|asn| asn.autonomous_system_number))
+    }
+
     pub fn lookup(&self, ip: IpAddr) -> Result<IpInfo, MaxMindDbError> {
+        self.lookup_localized(ip, "ru")
+    }
+
+    pub fn lookup_many(&self, ips: &[IpAddr]) -> Vec<Result<IpInfo, MaxMindDbError>> {
+        ips.iter().map(|ip| self.lookup(*ip)).collect()
+    }
+
+    /// Like [`GeoIp::lookup`], with `location` in `lang`, falling back to English
+    /// and then to whatever name is available.
+    pub fn lookup_localized(&self, ip: IpAddr, lang: &str) -> Result<IpInfo, MaxMindDbError> {
         let asn = if let Some(db) = &self.asn {
             db.lookup::<geoip2::Asn>(ip)?
         } else { None };
@@ -73,15 +175,33 @@ impl GeoIp {
                 .flatten())
             .flatten();
 
+        let city_name = city.as_ref()
+            .and_then(|c| c.city.as_ref())
+            .and_then(|c| c.names.as_ref())
+            .and_then(|names| names.get("en"))
+            .map(|name| name.to_string());
+
+        let country_name = country.as_ref()
+            .and_then(|c| c.country.as_ref())
+            .and_then(|c| c.names.as_ref())
+            .and_then(|names| names.get("en"))
+            .map(|name| name.to_string());
+
+        let coordinates = city.as_ref()
+            .and_then(|c| c.location.as_ref())
+            .and_then(|l| l.latitude.zip(l.longitude));
+
+        let asn_number = asn.as_ref().and_then(|asn| asn.autonomous_system_number);
+
         let location = match (city, country) {
             (Some(City { city: Some(city::City { names: Some(city), .. }),
                       country: Some(country::Country { names: Some(country), .. }), .. }), _) => {
-                let city = city.get("ru").unwrap_or(&"-");
-                let country = country.get("ru").unwrap_or(&"-");
+                let city = Self::localized(&city, lang).unwrap_or("-");
+                let country = Self::localized(&country, lang).unwrap_or("-");
                 format!("{}, {}", city, country)
             }
             (_, Some(Country { country: Some(country::Country { names: Some(country), .. }), .. })) => {
-                country.get("ru").unwrap_or(&"-").to_string()
+                Self::localized(&country, lang).unwrap_or("-").to_string()
             }
             (_, _) => "-".to_string(),
         };
@@ -90,8 +210,12 @@ impl GeoIp {
             location,
             country_code,
             city_geo_name_id,
-            asn: asn.clone().and_then(|asn| asn.autonomous_system_number)
-                .map(|asn| format!("AS{}", asn)),
+            latitude: coordinates.map(|(lat, _)| lat),
+            longitude: coordinates.map(|(_, lon)| lon),
+            city_name,
+            country_name,
+            asn_number,
+            asn: asn_number.map(|asn| format!("AS{}", asn)),
             organisation: asn.and_then(|asn| asn.autonomous_system_organization)
                 .map(|org| org.to_string()),
         })
@@ -100,16 +224,35 @@ impl GeoIp {
 
 #[async_trait]
 impl Updatable for GeoIp {
-    type Base = (Vec<u8>, Vec<u8>, Vec<u8>);
+    type Base = (Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
 
-    async fn download() -> Result<Self::Base, Error> {
-        Ok((fetch_db(Self::get_url("GEO_ASN", "https://git.io/GeoLite2-ASN.mmdb")).await?,
-            fetch_db(Self::get_url("GEO_COUNTRY", "https://git.io/GeoLite2-Country.mmdb")).await?,
-            fetch_db(Self::get_url("GEO_CITY", "https://git.io/GeoLite2-City.mmdb")).await?))
+    #[cfg(feature = "network")]
+    async fn download(progress: &Progress) -> Result<Self::Base, Error> {
+        Ok((Self::load_db("GEO_ASN_PATH", "GEO_ASN", "https://git.io/GeoLite2-ASN.mmdb", progress).await?,
+            Self::load_db("GEO_COUNTRY_PATH", "GEO_COUNTRY", "https://git.io/GeoLite2-Country.mmdb", progress).await?,
+            Self::load_db("GEO_CITY_PATH", "GEO_CITY", "https://git.io/GeoLite2-City.mmdb", progress).await?))
     }
 
-    async fn install(&mut self, (asn, country, city): Self::Base) -> Result<(), Error> {
-        self.update(asn, country, city)
-            .map_err(|e| Error::new(io::ErrorKind::Other, e))
+    /// All databases are parsed before any is swapped in, so a broken one
+    /// leaves the previous set untouched.
+    async fn install(&mut self, (asn, country, city): Self::Base) -> Result<(), UpdateError> {
+        let parse = |db: Option<Vec<u8>>| db.map(maxminddb::Reader::from_source).transpose();
+        let (asn, country, city) = (parse(asn)?, parse(country)?, parse(city)?);
+        for reader in [&asn, &country, &city].into_iter().flatten() {
+            if reader.metadata.node_count == 0 {
+                return Err(UpdateError::Invalid(format!("{} database is empty", reader.metadata.database_type)));
+            }
+        }
+        if asn.is_some() {
+            self.asn = asn;
+        }
+        if country.is_some() {
+            self.country = country;
+        }
+        if city.is_some() {
+            self.city = city;
+        }
+        self.installed_at = Some(Utc::now());
+        Ok(())
     }
 }
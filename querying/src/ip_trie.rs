@@ -0,0 +1,86 @@
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use prefix_trie::PrefixMap;
+
+/// IPv4 and IPv6 networks with a value each. Lookups in either direction walk the trie
+/// from the queried network, both for the networks covering it and the ones inside it.
+pub struct IpTrie<T> {
+    v4: PrefixMap<Ipv4Net, T>,
+    v6: PrefixMap<Ipv6Net, T>,
+}
+
+impl<T> IpTrie<T> {
+    pub fn new() -> IpTrie<T> {
+        IpTrie { v4: PrefixMap::new(), v6: PrefixMap::new() }
+    }
+
+    pub fn insert(&mut self, net: IpNet, data: T) -> Option<T> {
+        match net {
+            IpNet::V4(net) => self.v4.insert(net, data),
+            IpNet::V6(net) => self.v6.insert(net, data),
+        }
+    }
+
+    pub fn remove(&mut self, net: IpNet) -> Option<T> {
+        match net {
+            IpNet::V4(net) => self.v4.remove(&net),
+            IpNet::V6(net) => self.v6.remove(&net),
+        }
+    }
+
+    /// Value stored for exactly `net`.
+    pub fn get(&self, net: &IpNet) -> Option<&T> {
+        match net {
+            IpNet::V4(net) => self.v4.get(net),
+            IpNet::V6(net) => self.v6.get(net),
+        }
+    }
+
+    /// Every network, IPv4 first, each family ordered like [`IpNet::trunc`] of its networks.
+    pub fn iter(&self) -> impl Iterator<Item = (IpNet, &T)> {
+        self.v4.iter().map(|(net, data)| (IpNet::V4(*net), data))
+            .chain(self.v6.iter().map(|(net, data)| (IpNet::V6(*net), data)))
+    }
+
+    /// Most specific network containing `net`.
+    pub fn longest_match(&self, net: &IpNet) -> Option<(IpNet, &T)> {
+        match net {
+            IpNet::V4(net) => self.v4.get_lpm(net).map(|(net, data)| (IpNet::V4(*net), data)),
+            IpNet::V6(net) => self.v6.get_lpm(net).map(|(net, data)| (IpNet::V6(*net), data)),
+        }
+    }
+
+    /// Every network containing `net`, including `net` itself.
+    pub fn matches<'a>(&'a self, net: &'a IpNet) -> Vec<(IpNet, &'a T)> {
+        match net {
+            IpNet::V4(net) => self.v4.cover(net).map(|(net, data)| (IpNet::V4(*net), data)).collect(),
+            IpNet::V6(net) => self.v6.cover(net).map(|(net, data)| (IpNet::V6(*net), data)).collect(),
+        }
+    }
+
+    /// Every network lying inside `net`, including `net` itself.
+    pub fn within(&self, net: IpNet) -> Vec<(IpNet, &T)> {
+        match net {
+            IpNet::V4(net) => self.v4.children(net).map(|(net, data)| (IpNet::V4(*net), data)).collect(),
+            IpNet::V6(net) => self.v6.children(net).map(|(net, data)| (IpNet::V6(*net), data)).collect(),
+        }
+    }
+
+    /// Distinct IPv4 and IPv6 addresses the networks cover, overlaps counted once.
+    pub fn ip_count(&self) -> (u32, u128) {
+        let nets: Vec<IpNet> = self.iter().map(|(net, _)| net).collect();
+        let (mut v4, mut v6) = (0u32, 0u128);
+        for net in IpNet::aggregate(&nets) {
+            match net {
+                IpNet::V4(net) => v4 = v4.saturating_add(1u32.checked_shl(32 - net.prefix_len() as u32).unwrap_or(u32::MAX)),
+                IpNet::V6(net) => v6 = v6.saturating_add(1u128.checked_shl(128 - net.prefix_len() as u32).unwrap_or(u128::MAX)),
+            }
+        }
+        (v4, v6)
+    }
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> IpTrie<T> {
+        IpTrie::new()
+    }
+}
@@ -1,44 +1,104 @@
 use crate::geoip::{GeoIp, IpInfo};
-use crate::lists::{CdnList, NetworkRecord, RuBlacklist};
-use crate::resolver::{ResolveError, Resolver};
-use crate::target::Target;
-use crate::updater::Updatable;
+use crate::lists::{CdnList, DomainMatch, NetworkRecord, RuBlacklist};
+use crate::policy::{BlockPolicy, CheckSignals, DefaultPolicy};
+use crate::resolver::{DnsComparison, ResolveError, Resolution, Resolver};
+use crate::target::{Target, TargetError};
+use crate::updater::UpdateStatus;
+#[cfg(feature = "network")]
+use crate::updater::{DbStatus, Progress, Updatable};
+#[cfg(feature = "network")]
+use log::info;
+#[cfg(feature = "network")]
+use std::time::Duration;
+#[cfg(feature = "network")]
+use tokio::task::JoinHandle;
 use chrono::{DateTime, Utc};
 use ipnet::IpNet;
-use log::error;
+use futures_util::future::join_all;
+use log::{error, warn};
 use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use maxminddb::MaxMindDbError;
+use serde::Serialize;
 use thiserror::Error;
-use tokio::sync::{watch, RwLock};
+use tokio::join;
+use tokio::sync::{watch, Mutex, RwLock};
 
+mod explain;
+#[cfg(feature = "network")]
+mod fetch;
+mod ip_trie;
 pub mod geoip;
 pub mod lists;
+pub mod policy;
 pub mod resolver;
 pub mod updater;
 pub mod target;
 
 pub struct Checker {
     rx: watch::Receiver<Option<DateTime<Utc>>>,
+    #[cfg_attr(not(feature = "network"), allow(dead_code))]
     tx: watch::Sender<Option<DateTime<Utc>>>,
+    status: watch::Sender<UpdateStatus>,
     cdn_list: Arc<RwLock<CdnList>>,
     ru_blacklist: Arc<RwLock<RuBlacklist>>,
     geo_ip: Arc<RwLock<GeoIp>>,
     resolver: Resolver,
+    policy: Box<dyn BlockPolicy>,
+    on_check: Option<Box<CheckCallback>>,
+    checks: AtomicU64,
+    asn_index: Mutex<Option<AsnIndex>>,
+}
+
+/// RKN subnets grouped by the AS GeoIP attributes them to, as of the update finished at `built_for`.
+struct AsnIndex {
+    built_for: Option<DateTime<Utc>>,
+    subnets: Arc<HashMap<u32, Vec<IpNet>>>,
+}
+
+/// Called with every finished [`Checker::check`], see [`Checker::on_check`].
+pub type CheckCallback = dyn Fn(&Target, &Result<Check, CheckError>) + Send + Sync;
+
+/// Point-in-time snapshot of database sizes and activity, for monitoring.
+#[derive(Debug, Clone)]
+pub struct CheckerMetrics {
+    pub rkn_v4: u32,
+    pub rkn_v6: u128,
+    pub rkn_domains: usize,
+    pub cdn_v4: u32,
+    pub cdn_v6: u128,
+    /// `None` until the first update finishes
+    pub last_update_age_secs: Option<i64>,
+    /// Checks started since the process came up
+    pub checks_total: u64,
 }
 
 pub struct Check {
     pub verdict: CheckVerdict,
     pub geo: IpInfo,
     pub ips: Vec<IpAddr>,
+    pub cname_chain: Vec<String>,
     pub rkn_subnets: HashSet<IpNet>,
 }
 
+/// MX and NS hosts of a domain along with their own checks.
+pub struct RelatedCheck {
+    /// Mail exchangers, most preferred first
+    pub mx: Vec<RelatedHost>,
+    pub ns: Vec<RelatedHost>,
+}
+
+pub struct RelatedHost {
+    pub host: String,
+    pub check: Result<Check, CheckError>,
+}
+
 pub enum CheckVerdict {
     Clear,
     Blocked {
-        rkn_domain: Option<String>,
+        rkn_domain: Option<DomainMatch>,
         cdn_provider_subnets: HashMap<String, HashSet<NetworkRecord>>,
     },
 }
@@ -51,37 +111,165 @@ pub enum CheckError {
     GeoIpError,
     #[error("domain not found")]
     NotFound,
+    #[error(transparent)]
+    InvalidTarget(#[from] TargetError),
+}
+
+/// Whether a database has data installed, and how many records it holds.
+#[derive(Debug, Clone, Serialize)]
+pub struct DbReadiness {
+    pub loaded: bool,
+    pub records: u64,
+}
+
+impl DbReadiness {
+    fn new(records: u64) -> DbReadiness {
+        DbReadiness { loaded: records > 0, records }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub geo_ip: DbReadiness,
+    pub rkn: DbReadiness,
+    pub cdn: DbReadiness,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.geo_ip.loaded && self.rkn.loaded && self.cdn.loaded
+    }
 }
 
 impl Checker {
     pub async fn new() -> Checker {
         let (tx, rx) = watch::channel(None);
 
+        let resolver = match Resolver::from_env() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                error!("{}, falling back to Quad9", e);
+                Resolver::new().await
+            }
+        };
+
         Checker {
             rx,
             tx,
+            status: watch::Sender::new(UpdateStatus::default()),
             cdn_list: Arc::new(RwLock::new(CdnList::new())),
             ru_blacklist: Arc::new(RwLock::new(RuBlacklist::new())),
             geo_ip: Arc::new(RwLock::new(GeoIp::new())),
-            resolver: Resolver::new().await,
+            resolver,
+            policy: Box::new(DefaultPolicy),
+            on_check: None,
+            checks: AtomicU64::new(0),
+            asn_index: Mutex::new(None),
         }
     }
 
+    /// Decides verdicts with `policy` instead of [`DefaultPolicy`].
+    pub fn with_policy(mut self, policy: Box<dyn BlockPolicy>) -> Checker {
+        self.policy = policy;
+        self
+    }
+
+    /// Calls `callback` with the outcome of every check, e.g. for metrics or an audit trail.
+    /// It runs on the checking task, so anything slow should be handed off elsewhere.
+    pub fn on_check(mut self, callback: Box<CheckCallback>) -> Checker {
+        self.on_check = Some(callback);
+        self
+    }
+
     pub async fn geo_ip(&self, ip: IpAddr) -> Result<IpInfo, MaxMindDbError> {
         self.geo_ip.read().await.lookup(ip)
     }
 
+    /// Like [`Checker::geo_ip`], with the location named in `lang`.
+    pub async fn geo_ip_localized(&self, ip: IpAddr, lang: &str) -> Result<IpInfo, MaxMindDbError> {
+        self.geo_ip.read().await.lookup_localized(ip, lang)
+    }
+
+    /// Geo-tags every address under a single read of the database.
+    pub async fn geo_ip_many(&self, ips: &[IpAddr]) -> Vec<Result<IpInfo, MaxMindDbError>> {
+        self.geo_ip.read().await.lookup_many(ips)
+    }
+
+    /// RKN subnets whose network address GeoIP attributes to `asn`.
+    /// Subnets spanning several ASes count for the one announcing their first address.
+    /// The index behind this is built on the first call after each update.
+    pub async fn asn_blocked_subnets(&self, asn: u32) -> Vec<IpNet> {
+        let last_update = self.last_update();
+        let mut index = self.asn_index.lock().await;
+        let subnets = match index.as_ref() {
+            Some(index) if index.built_for == last_update => index.subnets.clone(),
+            _ => {
+                let subnets = Arc::new(self.build_asn_index().await);
+                *index = Some(AsnIndex { built_for: last_update, subnets: subnets.clone() });
+                subnets
+            }
+        };
+        drop(index);
+        subnets.get(&asn).cloned().unwrap_or_default()
+    }
+
+    async fn build_asn_index(&self) -> HashMap<u32, Vec<IpNet>> {
+        let geo_ip = self.geo_ip.read().await;
+        let ru_blacklist = self.ru_blacklist.read().await;
+        let mut subnets: HashMap<u32, Vec<IpNet>> = HashMap::new();
+        let mut failed = 0;
+        for net in ru_blacklist.nets() {
+            match geo_ip.asn(net.network()) {
+                Ok(Some(asn)) => subnets.entry(asn).or_default().push(net),
+                Ok(None) => {}
+                Err(_) => failed += 1,
+            }
+        }
+        if failed > 0 {
+            warn!("ASN lookup failed for {} RKN subnets, left them out of the index", failed);
+        }
+        subnets
+    }
+
     pub async fn check(&self, target: Target) -> Result<Check, CheckError> {
-        let ips = match target.resolve(&self.resolver).await {
-            Ok(ips) => ips,
+        self.check_with_resolver(target, &self.resolver).await
+    }
+
+    /// Configured resolver or comparison upstream (`RESOLVER_COMPARE`) named `name`.
+    pub fn resolver(&self, name: &str) -> Option<&Resolver> {
+        self.resolver.by_name(name)
+    }
+
+    /// Like [`Checker::check`], resolving the target through `resolver` instead of the default one.
+    pub async fn check_with_resolver(&self, target: Target, resolver: &Resolver) -> Result<Check, CheckError> {
+        let check = self.resolve_and_check(&target, resolver).await;
+        if let Some(on_check) = &self.on_check {
+            on_check(&target, &check);
+        }
+        check
+    }
+
+    async fn resolve_and_check(&self, target: &Target, resolver: &Resolver) -> Result<Check, CheckError> {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        target.validate()?;
+        let resolution = match target.resolve(resolver).await {
+            Ok(resolution) => resolution,
             Err(ResolveError::NxDomain) => {
                 return Err(CheckError::NotFound);
             }
+            Err(e @ (ResolveError::ServFail | ResolveError::Refused)) => {
+                return Err(CheckError::ResolveError(e));
+            }
             Err(e) => {
                 error!("{}", e);
                 return Err(CheckError::ResolveError(e));
             },
         };
+        self.check_resolved(target, resolution).await
+    }
+
+    /// Runs the GeoIP, CDN and RKN lookups on an already resolved target.
+    async fn check_resolved(&self, target: &Target, Resolution { ips, cname_chain }: Resolution) -> Result<Check, CheckError> {
         let geo_ip = self.geo_ip.read().await;
         let geo = match ips.get(0).map(|ip| geo_ip.lookup(ip.clone())) {
             None => IpInfo::default(),
@@ -95,7 +283,7 @@ impl Checker {
 
         let cdn_list = self.cdn_list.read().await;
         ips.iter()
-            .filter_map(|ip| cdn_list.contains(ip))
+            .flat_map(|ip| cdn_list.contains_all(ip))
             .map(|ip| (match &ip.region {
                 None => ip.provider.clone(),
                 Some(region) => format!("{} ({})", ip.provider, region),
@@ -105,7 +293,7 @@ impl Checker {
             });
 
         let ru_blacklist = self.ru_blacklist.read().await;
-        let domain = match &target {
+        let rkn_domain = match target {
             Target::Domain(domain) => ru_blacklist.contains_domain(domain),
             _ => None
         };
@@ -114,57 +302,107 @@ impl Checker {
             .filter_map(|ip| ru_blacklist.contains_ip(ip))
             .collect();
 
+        let signals = CheckSignals { rkn_domain, cdn_provider_subnets, rkn_subnets };
         Ok(Check {
-            verdict: match (domain, cdn_provider_subnets.is_empty()) {
-                (None, true) => CheckVerdict::Clear,
-                (domain, _) => CheckVerdict::Blocked {
-                    rkn_domain: domain,
-                    cdn_provider_subnets,
-                },
-            },
-            rkn_subnets,
+            verdict: self.policy.verdict(&signals),
+            rkn_subnets: signals.rkn_subnets,
             geo,
-            ips
+            ips,
+            cname_chain,
         })
     }
 
+    /// Checks the MX and NS hosts of `domain` like targets of their own, since mail
+    /// and DNS can be blocked while the website itself loads fine.
+    pub async fn check_related(&self, domain: &str) -> RelatedCheck {
+        let (mx, ns) = join!(self.resolver.lookup_mx(domain), self.resolver.lookup_ns(domain));
+        let hosts = |kind: &str, hosts: Result<Vec<String>, ResolveError>| match hosts {
+            Ok(hosts) => hosts,
+            Err(ResolveError::NxDomain) => vec![],
+            Err(e) => {
+                warn!("{} lookup for {} failed: {}", kind, domain, e);
+                vec![]
+            }
+        };
+        let (mx, ns) = join!(
+            self.check_hosts(hosts("MX", mx)),
+            self.check_hosts(hosts("NS", ns)),
+        );
+        RelatedCheck { mx, ns }
+    }
+
+    async fn check_hosts(&self, hosts: Vec<String>) -> Vec<RelatedHost> {
+        join_all(hosts.into_iter().map(|host| async move {
+            let target = Target::Domain(host.clone());
+            let check = match target.resolve(&self.resolver).await {
+                Ok(resolution) => self.check_resolved(&target, resolution).await,
+                Err(ResolveError::NxDomain) => Err(CheckError::NotFound),
+                Err(e) => Err(CheckError::ResolveError(e)),
+            };
+            RelatedHost { host, check }
+        })).await
+    }
+
+    pub async fn compare_dns(&self, domain: &str) -> DnsComparison {
+        self.resolver.compare(domain).await
+    }
+
+    /// Whether the comparison upstreams (`RESOLVER_COMPARE`) disagree with `ips`, the answer a
+    /// check already got for `domain`. `None` when no upstream is configured or none answered.
+    pub async fn dns_divergence(&self, domain: &str, ips: &[IpAddr]) -> Option<bool> {
+        self.resolver.diverges_from(domain, ips).await
+    }
+
+    pub fn clear_dns_cache(&self) {
+        self.resolver.clear_cache();
+    }
+
     pub fn last_update(&self) -> Option<DateTime<Utc>> {
         self.rx.borrow().clone()
     }
 
+    /// Notified with the new timestamp whenever `update_all` finishes.
+    pub fn subscribe_updates(&self) -> watch::Receiver<Option<DateTime<Utc>>> {
+        self.rx.clone()
+    }
+
+    /// Per-database progress of the current or last `update_all`.
+    pub fn update_status(&self) -> watch::Receiver<UpdateStatus> {
+        self.status.subscribe()
+    }
+
+    /// Downloads all databases concurrently, installing each one as soon as it arrives.
+    /// A failed database is logged and does not hold back the others.
+    #[cfg(feature = "network")]
     pub async fn update_all(&self) {
-        match GeoIp::download().await {
-            Ok(base) => {
-                if let Err(e) = self.geo_ip.write().await.install(base).await {
-                    error!("Failed to update GeoIP: {}", e);
-                }
-            }
-            Err(e) => {
-                error!("Failed to download GeoIP: {}", e);
-            }
-        }
-        match RuBlacklist::download().await {
-            Ok(base) => {
-                if let Err(e) = self.ru_blacklist.write().await.install(base).await {
-                    error!("Failed to update RKN: {}", e);
-                }
-            }
-            Err(e) => {
-                error!("Failed to download RKN: {}", e);
-            }
-        }
+        self.update_handles().update_all().await
+    }
 
-        match CdnList::download().await {
-            Ok(base) => {
-                if let Err(e) = self.cdn_list.write().await.install(base).await {
-                    error!("Failed to update CDN: {}", e);
-                }
-            }
-            Err(e) => {
-                error!("Failed to download CDN: {}", e);
+    /// Runs `update_all` right away and then every `interval` plus a random delay
+    /// of up to `jitter`, so that instances started together don't hit the mirrors at once.
+    #[cfg(feature = "network")]
+    pub fn spawn_auto_update(&self, interval: Duration, jitter: Duration) -> JoinHandle<()> {
+        let handles = self.update_handles();
+        tokio::spawn(async move {
+            info!("Refreshing DB every {:?} (+ up to {:?})", interval, jitter);
+            loop {
+                info!("Updating all DBs");
+                handles.update_all().await;
+                info!("Updated databases");
+                tokio::time::sleep(interval + jitter.mul_f64(fastrand::f64())).await;
             }
+        })
+    }
+
+    #[cfg(feature = "network")]
+    fn update_handles(&self) -> UpdateHandles {
+        UpdateHandles {
+            tx: self.tx.clone(),
+            status: self.status.clone(),
+            cdn_list: self.cdn_list.clone(),
+            ru_blacklist: self.ru_blacklist.clone(),
+            geo_ip: self.geo_ip.clone(),
         }
-        self.tx.send(Some(Utc::now())).unwrap();
     }
 
     pub async fn total_domains(&self) -> usize {
@@ -175,4 +413,82 @@ impl Checker {
         (self.cdn_list.read().await.v4_count() + self.ru_blacklist.read().await.v4_count()) as usize
     }
 
+    pub async fn readiness(&self) -> Readiness {
+        let ru_blacklist = self.ru_blacklist.read().await;
+        Readiness {
+            geo_ip: DbReadiness::new(self.geo_ip.read().await.node_count()),
+            // both halves of the RKN list are required
+            rkn: DbReadiness {
+                loaded: ru_blacklist.net_count > 0 && ru_blacklist.domain_count > 0,
+                records: (ru_blacklist.net_count + ru_blacklist.domain_count) as u64,
+            },
+            cdn: DbReadiness::new(self.cdn_list.read().await.record_count as u64),
+        }
+    }
+
+    /// When each database was last installed, `None` if it never was.
+    pub async fn databases_age(&self) -> HashMap<&'static str, Option<DateTime<Utc>>> {
+        HashMap::from([
+            ("geo_ip", self.geo_ip.read().await.installed_at),
+            ("rkn", self.ru_blacklist.read().await.installed_at),
+            ("cdn", self.cdn_list.read().await.installed_at),
+        ])
+    }
+
+    pub async fn metrics(&self) -> CheckerMetrics {
+        let cdn_list = self.cdn_list.read().await;
+        let ru_blacklist = self.ru_blacklist.read().await;
+        CheckerMetrics {
+            rkn_v4: ru_blacklist.v4_count(),
+            rkn_v6: ru_blacklist.v6_count(),
+            rkn_domains: ru_blacklist.domain_count,
+            cdn_v4: cdn_list.v4_count(),
+            cdn_v6: cdn_list.v6_count(),
+            last_update_age_secs: self.last_update().map(|t| (Utc::now() - t).num_seconds()),
+            checks_total: self.checks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The parts of a [`Checker`] an update touches, so it can run in a background task.
+#[cfg(feature = "network")]
+struct UpdateHandles {
+    tx: watch::Sender<Option<DateTime<Utc>>>,
+    status: watch::Sender<UpdateStatus>,
+    cdn_list: Arc<RwLock<CdnList>>,
+    ru_blacklist: Arc<RwLock<RuBlacklist>>,
+    geo_ip: Arc<RwLock<GeoIp>>,
+}
+
+#[cfg(feature = "network")]
+impl UpdateHandles {
+    async fn update_all(&self) {
+        self.status.send_replace(UpdateStatus::default());
+        join!(
+            Self::update_one("GeoIP", &self.geo_ip, Progress::new(self.status.clone(), |s| &mut s.geo_ip)),
+            Self::update_one("RKN", &self.ru_blacklist, Progress::new(self.status.clone(), |s| &mut s.rkn)),
+            Self::update_one("CDN", &self.cdn_list, Progress::new(self.status.clone(), |s| &mut s.cdn)),
+        );
+        self.tx.send_replace(Some(Utc::now()));
+    }
+
+    async fn update_one<T: Updatable>(name: &str, db: &RwLock<T>, progress: Progress) {
+        let result = match T::download(&progress).await {
+            Ok(base) => {
+                progress.set(DbStatus::Parsing);
+                db.write().await.install(base).await.map_err(|e| {
+                    error!("Failed to update {}, kept previous database: {}", name, e);
+                    e.to_string()
+                })
+            }
+            Err(e) => {
+                error!("Failed to download {}: {}", name, e);
+                Err(e.to_string())
+            }
+        };
+        progress.set(match result {
+            Ok(()) => DbStatus::Installed,
+            Err(error) => DbStatus::Failed { error },
+        });
+    }
 }
@@ -1,27 +1,48 @@
-use crate::updater::{fetch_db, Updatable};
+use crate::ip_trie::IpTrie;
+use crate::updater::{Updatable, UpdateError};
+#[cfg(feature = "network")]
+use crate::updater::{fetch_db, Progress};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use ipnet::IpNet;
-use ipnet_trie::IpnetTrie;
-use log::info;
+use log::{info, warn};
 use serde::{de, Deserialize, Deserializer, Serializer};
 use std::collections::VecDeque;
-use std::io;
-use std::io::{BufRead, Error, Read};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::fs::File;
+use std::io::{BufRead, Read};
+#[cfg(feature = "network")]
+use std::io::Error;
 use std::net::IpAddr;
+use std::path::Path;
 use std::str::FromStr;
 use trie_rs::map::{Trie, TrieBuilder};
 
 pub struct CdnList {
-    trie: IpnetTrie<NetworkRecord>,
+    /// Records by range, several when providers list the same range
+    trie: IpTrie<Vec<NetworkRecord>>,
+    pub record_count: usize,
+    /// When the current list was installed or last found unchanged
+    pub installed_at: Option<DateTime<Utc>>,
+    /// Hash of the installed records, to skip updates that change nothing
+    content_hash: Option<u64>,
 }
 
+/// A CDN range. Columns are matched by header name, so lists with
+/// a different column order or common alternative names load as well.
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Eq, PartialEq, Hash)]
 pub struct NetworkRecord {
+    #[serde(alias = "name", alias = "cdn", alias = "organization")]
     pub provider: String,
+    #[serde(alias = "network", alias = "prefix", alias = "subnet", alias = "range")]
     #[serde(deserialize_with = "deserialize_ip_net")]
     #[serde(serialize_with = "serialize_ip_net")]
     pub cidr: IpNet,
+    #[serde(default, alias = "location")]
     pub region: Option<String>,
+    /// List the range was loaded from, unless the row names one itself
+    #[serde(default)]
+    pub source: Option<String>,
 }
 
 fn deserialize_ip_net<'de, D>(deserializer: D) -> Result<IpNet, D::Error>
@@ -39,21 +60,135 @@ where
     serializer.serialize_str(&ip_net.to_string())
 }
 
+fn validate(what: &str, records: usize, min_records: usize, v4: u32) -> Result<(), UpdateError> {
+    if records < min_records {
+        return Err(UpdateError::Invalid(format!("{} {}, expected at least {}", records, what, min_records)));
+    }
+    if v4 == 0 {
+        return Err(UpdateError::Invalid(format!("{} cover no IPv4 addresses", what)));
+    }
+    Ok(())
+}
+
+/// Networks [`sync_trie`] added or replaced, to put the trie back as it was
+/// when the new list turns out to be invalid.
+struct TrieUndo<T> {
+    added: Vec<IpNet>,
+    replaced: Vec<(IpNet, T)>,
+}
+
+impl<T> TrieUndo<T> {
+    fn undo(self, trie: &mut IpTrie<T>) {
+        for net in self.added {
+            trie.remove(net);
+        }
+        for (net, data) in self.replaced {
+            trie.insert(net, data);
+        }
+    }
+}
+
+/// Makes `trie` hold exactly `entries`, removing and inserting only the networks
+/// that changed, so a refresh doesn't keep a second full trie in memory. `entries`
+/// must be sorted by [`IpNet::trunc`] without repeats, the order the trie iterates in,
+/// so networks missing from the new list are found by walking both side by side.
+fn sync_trie<T: PartialEq>(trie: &mut IpTrie<T>, entries: Vec<(IpNet, T)>) -> TrieUndo<T> {
+    let mut stale = vec![];
+    let mut listed = entries.iter().map(|(net, _)| net.trunc()).peekable();
+    for (net, _) in trie.iter() {
+        let key = net.trunc();
+        while listed.next_if(|listed| *listed < key).is_some() {}
+        if listed.peek() != Some(&key) {
+            stale.push(net);
+        }
+    }
+
+    let mut undo = TrieUndo { added: vec![], replaced: vec![] };
+    for net in stale {
+        if let Some(data) = trie.remove(net) {
+            undo.replaced.push((net, data));
+        }
+    }
+    for (net, data) in entries {
+        if trie.get(&net) == Some(&data) {
+            continue;
+        }
+        match trie.insert(net, data) {
+            Some(old) => undo.replaced.push((net, old)),
+            None => undo.added.push(net),
+        }
+    }
+    info!("trie diff: {} added, {} removed or changed", undo.added.len(), undo.replaced.len());
+    undo
+}
+
 impl CdnList {
     pub fn new() -> CdnList{
-        CdnList { trie: IpnetTrie::new() }
+        CdnList { trie: IpTrie::new(), record_count: 0, installed_at: None, content_hash: None }
+    }
+
+    /// Loads a hand-maintained list, which only has to be non-empty.
+    pub fn from_path(path: &Path) -> Result<CdnList, UpdateError> {
+        let mut list = CdnList::new();
+        list.update(&path.display().to_string(), File::open(path)?, 1)?;
+        Ok(list)
     }
 
-    pub fn update<R: Read>(&mut self, list_reader: R) -> Result<(), Error>  {
-        let mut trie = IpnetTrie::new();
+    /// Rows that fail to parse, e.g. with an invalid CIDR, are logged and skipped.
+    /// The list is kept as is unless the new one has at least `min_records` rows
+    /// and a non-zero IPv4 count. `source` identifies the list in each record.
+    /// Only changed ranges are touched, and an identical list leaves the trie alone.
+    pub fn update<R: Read>(&mut self, source: &str, list_reader: R, min_records: usize) -> Result<(), UpdateError>  {
+        let mut rows = vec![];
+        let mut hasher = DefaultHasher::new();
         let mut rdr = csv::Reader::from_reader(list_reader);
+        let mut records = 0;
+        let mut skipped = 0;
         for result in rdr.deserialize() {
-            let record: NetworkRecord = result?;
-            trie.insert(record.cidr, record);
+            match result {
+                Ok(record) => {
+                    let mut record: NetworkRecord = record;
+                    record.source.get_or_insert_with(|| source.to_string());
+                    record.hash(&mut hasher);
+                    rows.push(record);
+                    records += 1;
+                }
+                Err(e) => {
+                    warn!("Skipping malformed CDN row: {}", e);
+                    skipped += 1;
+                }
+            }
+        }
+        let hash = hasher.finish();
+        if self.content_hash == Some(hash) {
+            info!("CDN list unchanged, skipped rows: {}", skipped);
+            self.installed_at = Some(Utc::now());
+            return Ok(());
+        }
+
+        // Stable, so providers sharing a range stay in list order
+        rows.sort_by_key(|record| record.cidr.trunc());
+        let mut entries: Vec<(IpNet, Vec<NetworkRecord>)> = vec![];
+        for record in rows {
+            match entries.last_mut() {
+                Some((cidr, same_range)) if cidr.trunc() == record.cidr.trunc() => {
+                    if !same_range.contains(&record) {
+                        same_range.push(record);
+                    }
+                }
+                _ => entries.push((record.cidr, vec![record])),
+            }
+        }
+        let undo = sync_trie(&mut self.trie, entries);
+        let (v4, v6) = self.trie.ip_count();
+        info!("ip count: v4={}, v6={}, skipped rows: {}", v4, v6, skipped);
+        if let Err(e) = validate("CDN rows", records, min_records, v4) {
+            undo.undo(&mut self.trie);
+            return Err(e);
         }
-        let (v4, v6) = trie.ip_count();
-        info!("ip count: v4={}, v6={}", v4, v6);
-        self.trie = trie;
+        self.record_count = records;
+        self.content_hash = Some(hash);
+        self.installed_at = Some(Utc::now());
         Ok(())
     }
 
@@ -61,31 +196,127 @@ impl CdnList {
         self.trie.ip_count().0
     }
 
+    pub fn v6_count(&self) -> u128 {
+        self.trie.ip_count().1
+    }
+
+    /// Most specific range containing `ip`, the first listed one if several providers share it.
     pub fn contains(&self, ip: &IpAddr) -> Option<NetworkRecord> {
-        self.trie.longest_match(&IpNet::from(*ip)).map(|(_, net)| net.clone())
+        self.trie.longest_match(&IpNet::from(*ip)).and_then(|(_, records)| records.first().cloned())
+    }
+
+    /// Every range containing `ip`, for when providers' ranges overlap.
+    pub fn contains_all(&self, ip: &IpAddr) -> Vec<NetworkRecord> {
+        self.trie.matches(&IpNet::from(*ip)).into_iter()
+            .flat_map(|(_, records)| records.iter().cloned())
+            .collect()
+    }
+
+    /// Every range lying inside `net`, including `net` itself.
+    pub fn records_within(&self, net: IpNet) -> Vec<NetworkRecord> {
+        self.trie.within(net).into_iter()
+            .flat_map(|(_, records)| records.iter().cloned())
+            .collect()
     }
 }
 
 #[async_trait]
 impl Updatable for CdnList {
-    type Base = VecDeque<u8>;
+    /// Source URL and the list itself
+    type Base = (String, VecDeque<u8>);
 
-    async fn download() -> Result<Self::Base, Error> {
-        Ok(VecDeque::from(fetch_db(Self::get_url(
+    #[cfg(feature = "network")]
+    async fn download(progress: &Progress) -> Result<Self::Base, Error> {
+        let url = Self::get_url(
             "CDN_SOURCE",
             "https://raw.githubusercontent.com/123jjck/cdn-ip-ranges/refs/heads/main/all/all.csv"
-        )).await?))
+        );
+        let list = VecDeque::from(fetch_db(url.as_str(), progress).await?);
+        Ok((url, list))
     }
 
-    async fn install(&mut self, base: Self::Base) -> Result<(), Error> {
-        self.update(base)
+    async fn install(&mut self, (source, list): Self::Base) -> Result<(), UpdateError> {
+        self.update(&source, list, Self::min_records("CDN_MIN_RECORDS", 100))
+    }
+}
+
+/// Layout of the RKN subnet list, selected with the `RKN_FORMAT` env var.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetListFormat {
+    /// One CIDR or address per line
+    Lst,
+    /// antifilter "ipsum" dump: a CIDR followed by annotations, `#` comments
+    Ipsum,
+    /// Addresses in the first column, several of them joined with `|`
+    Csv,
+}
+
+impl NetListFormat {
+    pub fn from_env() -> NetListFormat {
+        match std::env::var("RKN_FORMAT").unwrap_or_default().to_lowercase().as_str() {
+            "" | "lst" => NetListFormat::Lst,
+            "ipsum" => NetListFormat::Ipsum,
+            "csv" => NetListFormat::Csv,
+            other => {
+                warn!("Unknown RKN_FORMAT {}, using lst", other);
+                NetListFormat::Lst
+            }
+        }
+    }
+
+    /// Networks listed on a single line; blank and comment lines have none.
+    pub(crate) fn parse_line(&self, line: &str) -> Result<Vec<IpNet>, String> {
+        let line = line.trim();
+        match self {
+            NetListFormat::Lst if line.is_empty() => Ok(vec![]),
+            NetListFormat::Lst => Ok(vec![Self::parse_net(line)?]),
+            NetListFormat::Ipsum => match line.split('#').next().unwrap_or_default().split_whitespace().next() {
+                None => Ok(vec![]),
+                Some(net) => Ok(vec![Self::parse_net(net)?]),
+            },
+            NetListFormat::Csv => line.split([',', ';']).next().unwrap_or_default()
+                .split('|')
+                .map(str::trim)
+                .filter(|net| !net.is_empty())
+                .map(Self::parse_net)
+                .collect(),
+        }
+    }
+
+    fn parse_net(net: &str) -> Result<IpNet, String> {
+        IpNet::from_str(net)
+            .or_else(|_| IpAddr::from_str(net).map(IpNet::from))
+            .map_err(|e| format!("{}: {}", net, e))
+    }
+}
+
+/// Registry entry that matched a domain.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub enum DomainMatch {
+    /// The domain itself is listed
+    Exact(String),
+    /// One of its parent domains is listed, blocking every subdomain
+    Parent(String),
+}
+
+impl DomainMatch {
+    pub fn domain(&self) -> &str {
+        match self {
+            DomainMatch::Exact(domain) | DomainMatch::Parent(domain) => domain,
+        }
     }
 }
 
 pub struct RuBlacklist {
-    ip_trie: IpnetTrie<()>,
+    ip_trie: IpTrie<()>,
     domain_trie: Trie<String, String>,
+    /// Networks in the trie, after `RKN_AGGREGATE` merged them
+    pub net_count: usize,
     pub domain_count: usize,
+    /// When the current lists were installed or last found unchanged
+    pub installed_at: Option<DateTime<Utc>>,
+    /// Hash of the installed lists, to skip updates that change nothing
+    content_hash: Option<u64>,
 }
 
 impl RuBlacklist {
@@ -93,32 +324,78 @@ impl RuBlacklist {
         RuBlacklist {
             ip_trie: Default::default(),
             domain_trie: TrieBuilder::new().build(),
-            domain_count: 0
+            net_count: 0,
+            domain_count: 0,
+            installed_at: None,
+            content_hash: None,
         }
     }
 
-    pub fn update<R: BufRead>(&mut self, ip_reader: R, domain_reader: R, custom_domains_reader: R) -> Result<(), Error>  {
-        let mut ip_trie = IpnetTrie::new();
-        for net in ip_reader.lines() {
-            let net = net?;
-            let net = IpNet::from_str(&net)
-                .map_err(|e| Error::new(io::ErrorKind::InvalidData, e))?;
-            ip_trie.insert(net, ());
+    /// Malformed lines of the subnet list are logged and skipped. Both lists are kept
+    /// as is unless the new subnet and domain lists have at least `RKN_MIN_RECORDS`
+    /// entries each and the subnets cover some IPv4 space. Only changed subnets are
+    /// touched, and identical lists skip the rebuild entirely. The domain trie can't be
+    /// edited in place, so it is still rebuilt whenever anything changed.
+    pub fn update<R: BufRead>(&mut self, format: NetListFormat, ip_reader: R, domain_reader: R, custom_domains_reader: R) -> Result<(), UpdateError>  {
+        let min_records = Self::min_records("RKN_MIN_RECORDS", 1000);
+        let aggregate = std::env::var("RKN_AGGREGATE").is_ok_and(|v| matches!(v.as_str(), "1" | "true"));
+        let mut hasher = DefaultHasher::new();
+        (format, aggregate).hash(&mut hasher);
+        let mut nets = vec![];
+        let mut malformed = 0;
+        for line in ip_reader.lines() {
+            let line = line?;
+            line.hash(&mut hasher);
+            match format.parse_line(&line) {
+                Ok(line_nets) => nets.extend(line_nets),
+                Err(e) => {
+                    warn!("Skipping malformed RKN line: {}", e);
+                    malformed += 1;
+                }
+            }
         }
-        let (v4, v6) = ip_trie.ip_count();
-        info!("ip count: v4={}, v6={}", v4, v6);
-        self.ip_trie = ip_trie;
 
         let mut domain_trie = TrieBuilder::new();
         let mut count = 0;
         for domain in domain_reader.lines().chain(custom_domains_reader.lines()) {
             let domain = domain?;
+            domain.hash(&mut hasher);
             domain_trie.insert(Self::domain_chunks(&domain), domain);
             count += 1;
         }
+        let hash = hasher.finish();
+        if self.content_hash == Some(hash) {
+            info!("RKN lists unchanged, malformed lines: {}", malformed);
+            self.installed_at = Some(Utc::now());
+            return Ok(());
+        }
         info!("domain count: {}", count);
+        if count < min_records {
+            return Err(UpdateError::Invalid(format!("{} RKN domains, expected at least {}", count, min_records)));
+        }
+
+        let listed = nets.len();
+        // Merges adjacent networks and drops ones contained in others, so each IP has a single match
+        if aggregate {
+            nets = IpNet::aggregate(&nets);
+            info!("Aggregated {} RKN subnets into {}", listed, nets.len());
+        }
+        nets.sort_unstable_by_key(IpNet::trunc);
+        nets.dedup_by_key(|net| net.trunc());
+        let nets_count = nets.len();
+        let undo = sync_trie(&mut self.ip_trie, nets.into_iter().map(|net| (net, ())).collect());
+        let (v4, v6) = self.ip_trie.ip_count();
+        info!("ip count: v4={}, v6={}, malformed lines: {}", v4, v6, malformed);
+        if let Err(e) = validate("RKN subnets", listed, min_records, v4) {
+            undo.undo(&mut self.ip_trie);
+            return Err(e);
+        }
+
+        self.net_count = nets_count;
         self.domain_count = count;
         self.domain_trie = domain_trie.build();
+        self.content_hash = Some(hash);
+        self.installed_at = Some(Utc::now());
         Ok(())
     }
 
@@ -126,6 +403,10 @@ impl RuBlacklist {
         self.ip_trie.ip_count().0
     }
 
+    pub fn v6_count(&self) -> u128 {
+        self.ip_trie.ip_count().1
+    }
+
     fn domain_chunks(domain: &str) -> Vec<String> {
         domain.split(".").collect::<Vec<_>>()
             .into_iter().map(|s| s.to_string())
@@ -136,9 +417,33 @@ impl RuBlacklist {
         self.ip_trie.longest_match(&IpNet::from(*ip)).map(|(ip, _)| ip)
     }
 
-    pub fn contains_domain(&self, domain: &str) -> Option<String> {
-        self.domain_trie.common_prefix_search(Self::domain_chunks(domain)).next()
-            .map(|(_, b): (Vec<_>, &String)| b).cloned()
+    /// Most specific matching network along with its prefix length, so a single-host
+    /// entry (/32, /128) can be told apart from a broad aggregate.
+    pub fn contains_ip_detailed(&self, ip: &IpAddr) -> Option<(IpNet, u8)> {
+        self.contains_ip(ip).map(|net| (net, net.prefix_len()))
+    }
+
+    /// Blocked networks lying inside `net`, including `net` itself.
+    pub fn matching_within(&self, net: IpNet) -> Vec<IpNet> {
+        self.ip_trie.within(net).into_iter()
+            .map(|(blocked, _)| blocked)
+            .collect()
+    }
+
+    /// Every blocked network in the trie.
+    pub fn nets(&self) -> impl Iterator<Item = IpNet> + '_ {
+        self.ip_trie.iter().map(|(net, _)| net)
+    }
+
+    pub fn contains_domain(&self, domain: &str) -> Option<DomainMatch> {
+        let chunks = Self::domain_chunks(domain);
+        let query_len = chunks.len();
+        self.domain_trie.common_prefix_search(chunks).next()
+            .map(|(matched, blocked): (Vec<String>, &String)| if matched.len() == query_len {
+                DomainMatch::Exact(blocked.clone())
+            } else {
+                DomainMatch::Parent(blocked.clone())
+            })
     }
 }
 
@@ -146,16 +451,64 @@ impl RuBlacklist {
 impl Updatable for RuBlacklist {
     type Base = (VecDeque<u8>, VecDeque<u8>, VecDeque<u8>);
 
-    async fn download() -> Result<Self::Base, Error> {
+    #[cfg(feature = "network")]
+    async fn download(progress: &Progress) -> Result<Self::Base, Error> {
         Ok((VecDeque::from(
-            fetch_db(Self::get_url("RKN_NETS", "https://antifilter.network/download/ipsum.lst")).await?),
+            fetch_db(Self::get_url("RKN_NETS", "https://antifilter.network/download/ipsum.lst"), progress).await?),
             VecDeque::from(
-            fetch_db(Self::get_url("RKN_DOMAINS", "https://antifilter.download/list/domains.lst")).await?),
+            fetch_db(Self::get_url("RKN_DOMAINS", "https://antifilter.download/list/domains.lst"), progress).await?),
             VecDeque::from(include_bytes!("../dist-domains.txt").to_vec())
         ))
     }
 
-    async fn install(&mut self, (nets, domains, custom_domains): Self::Base) -> Result<(), Error> {
-        self.update(nets, domains, custom_domains)
+    async fn install(&mut self, (nets, domains, custom_domains): Self::Base) -> Result<(), UpdateError> {
+        self.update(NetListFormat::from_env(), nets, domains, custom_domains)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blacklist(nets: &[&str]) -> RuBlacklist {
+        let mut list = RuBlacklist::new();
+        for net in nets {
+            list.ip_trie.insert(net.parse().unwrap(), ());
+        }
+        list
+    }
+
+    #[test]
+    fn single_host_match() {
+        let list = blacklist(&["10.0.0.0/9", "10.1.2.3/32"]);
+        let (net, prefix_len) = list.contains_ip_detailed(&"10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(net, "10.1.2.3/32".parse::<IpNet>().unwrap());
+        assert_eq!(prefix_len, 32);
+    }
+
+    #[test]
+    fn broad_match() {
+        let list = blacklist(&["10.0.0.0/9", "10.1.2.3/32"]);
+        let (net, prefix_len) = list.contains_ip_detailed(&"10.100.0.1".parse().unwrap()).unwrap();
+        assert_eq!(net, "10.0.0.0/9".parse::<IpNet>().unwrap());
+        assert_eq!(prefix_len, 9);
+        assert_eq!(list.contains_ip_detailed(&"10.200.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn sync_trie_diff() {
+        let nets = |nets: &[&str]| -> Vec<(IpNet, ())> { nets.iter().map(|net| (net.parse().unwrap(), ())).collect() };
+        let mut trie = IpTrie::new();
+        sync_trie(&mut trie, nets(&["10.0.0.0/8", "10.0.0.0/16", "10.128.0.0/9", "192.0.2.0/24"]));
+
+        let undo = sync_trie(&mut trie, nets(&["10.0.0.0/8", "10.64.0.0/10", "192.0.2.0/24", "2001:db8::/32"]));
+        let current: Vec<_> = trie.iter().map(|(net, _)| net.to_string()).collect();
+        assert_eq!(current, ["10.0.0.0/8", "10.64.0.0/10", "192.0.2.0/24", "2001:db8::/32"]);
+        assert_eq!(undo.added.len(), 2);
+        assert_eq!(undo.replaced.len(), 2);
+
+        undo.undo(&mut trie);
+        let restored: Vec<_> = trie.iter().map(|(net, _)| net.to_string()).collect();
+        assert_eq!(restored, ["10.0.0.0/8", "10.0.0.0/16", "10.128.0.0/9", "192.0.2.0/24"]);
     }
 }
@@ -0,0 +1,35 @@
+use crate::lists::{DomainMatch, NetworkRecord};
+use crate::CheckVerdict;
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet};
+
+/// Everything a check found about a target, before deciding whether it's blocked.
+#[derive(Debug, Clone)]
+pub struct CheckSignals {
+    pub rkn_domain: Option<DomainMatch>,
+    /// Matching CDN ranges keyed by provider, with the region in parentheses when known
+    pub cdn_provider_subnets: HashMap<String, HashSet<NetworkRecord>>,
+    /// RKN subnets the resolved addresses fall into
+    pub rkn_subnets: HashSet<IpNet>,
+}
+
+/// Turns check signals into a verdict, see [`Checker::with_policy`](crate::Checker::with_policy).
+pub trait BlockPolicy: Send + Sync {
+    fn verdict(&self, signals: &CheckSignals) -> CheckVerdict;
+}
+
+/// Blocked on an RKN domain match or any CDN match. RKN subnets alone
+/// are only reported, since they also cover addresses shared with unblocked sites.
+pub struct DefaultPolicy;
+
+impl BlockPolicy for DefaultPolicy {
+    fn verdict(&self, signals: &CheckSignals) -> CheckVerdict {
+        if signals.rkn_domain.is_none() && signals.cdn_provider_subnets.is_empty() {
+            return CheckVerdict::Clear;
+        }
+        CheckVerdict::Blocked {
+            rkn_domain: signals.rkn_domain.clone(),
+            cdn_provider_subnets: signals.cdn_provider_subnets.clone(),
+        }
+    }
+}
@@ -1,39 +1,393 @@
-use hickory_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
-use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ProtocolConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::name_server::{NameServerPool, TokioConnectionProvider};
+use hickory_resolver::proto::op::{Edns, Message, Query, ResponseCode};
+use hickory_resolver::proto::rr::rdata::opt::{ClientSubnet, EdnsOption};
+use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+use hickory_resolver::proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions};
+use hickory_resolver::proto::{NoRecords, ProtoError, ProtoErrorKind};
+use futures_util::future::join_all;
+use futures_util::StreamExt;
+use ipnet::IpNet;
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use url::Url;
 
 pub struct Resolver {
+    name: String,
     resolver: hickory_resolver::Resolver<TokioConnectionProvider>,
+    upstreams: Vec<Resolver>,
+    client_subnet: Option<SubnetClient>,
+}
+
+/// Sends raw queries carrying an EDNS Client Subnet option, which the
+/// high-level hickory resolver has no way to attach.
+struct SubnetClient {
+    subnet: IpNet,
+    pool: NameServerPool<TokioConnectionProvider>,
+}
+
+pub struct Resolution {
+    pub ips: Vec<IpAddr>,
+    /// CNAME targets followed while resolving, in answer order
+    pub cname_chain: Vec<String>,
+}
+
+impl Resolution {
+    fn push_records<'a>(&mut self, records: impl IntoIterator<Item = &'a Record>) {
+        for record in records {
+            match record.data() {
+                RData::CNAME(cname) => {
+                    let cname = cname.0.to_utf8().trim_end_matches('.').to_string();
+                    if !self.cname_chain.contains(&cname) {
+                        self.cname_chain.push(cname);
+                    }
+                }
+                data => self.ips.extend(data.ip_addr()),
+            }
+        }
+    }
+}
+
+/// Answers of every configured upstream for a single domain.
+#[derive(Serialize, Debug)]
+pub struct DnsComparison {
+    pub ips: HashMap<String, Vec<IpAddr>>,
+    /// Some pair of upstreams returned completely disjoint IP sets
+    pub diverged: bool,
 }
 
 #[derive(Error, Debug)]
 pub enum ResolveError {
     #[error("domain not found")]
     NxDomain,
+    #[error("resolver timed out")]
+    Timeout,
+    #[error("upstream returned SERVFAIL")]
+    ServFail,
+    #[error("upstream refused the query")]
+    Refused,
+    #[error("invalid resolver config: {0}")]
+    InvalidConfig(String),
     #[error("resolver error")]
     Other(#[from] Error),
 }
 
 impl Resolver {
     pub async fn new() -> Resolver {
-        let config = ResolverConfig::quad9_https();
-        let mut opts = ResolverOpts::default();
-        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Self::with_config(ResolverConfig::quad9_https(), Self::default_opts()).named("quad9")
+    }
+
+    /// Quad9 DoH with a per-request `timeout` and number of `attempts`.
+    pub fn new_with_timeout(timeout: Duration, attempts: usize) -> Resolver {
+        let mut opts = Self::default_opts();
+        opts.timeout = timeout;
+        opts.attempts = attempts;
+        Self::with_config(ResolverConfig::quad9_https(), opts).named("quad9")
+    }
+
+    /// Quad9 DoH keeping up to `cache_size` answers cached according to their TTL.
+    pub fn new_with_cache_size(cache_size: usize) -> Resolver {
+        let mut opts = Self::default_opts();
+        opts.cache_size = cache_size;
+        Self::with_config(ResolverConfig::quad9_https(), opts).named("quad9")
+    }
+
+    pub fn with_config(config: ResolverConfig, opts: ResolverOpts) -> Resolver {
         let resolver = hickory_resolver::Resolver::builder_with_config(config, TokioConnectionProvider::default())
             .with_options(opts)
             .build();
-        Resolver { resolver }
+        Resolver { name: "custom".to_string(), resolver, upstreams: vec![], client_subnet: None }
+    }
+
+    /// Builds a resolver from the `RESOLVER` env var, defaulting to Quad9 DoH.
+    /// Comma-separated names in `RESOLVER_COMPARE` are added as comparison upstreams,
+    /// and `RESOLVER_CLIENT_SUBNET` sets an EDNS Client Subnet.
+    pub fn from_env() -> Result<Resolver, ResolveError> {
+        let mut resolver = Self::from_name(&std::env::var("RESOLVER").unwrap_or("quad9".to_string()))?;
+        if let Ok(subnet) = std::env::var("RESOLVER_CLIENT_SUBNET") {
+            let subnet = IpNet::from_str(&subnet)
+                .map_err(|e| ResolveError::InvalidConfig(format!("{subnet}: {e}")))?;
+            resolver = resolver.with_client_subnet(subnet);
+        }
+        if let Ok(upstreams) = std::env::var("RESOLVER_COMPARE") {
+            for upstream in upstreams.split(',').filter(|u| !u.trim().is_empty()) {
+                resolver = resolver.with_upstream(upstream)?;
+            }
+        }
+        Ok(resolver)
+    }
+
+    /// Accepts `quad9`, `google`, `cloudflare` (DoH), their `-tls` variants (DoT, for
+    /// networks filtering DoH on 443 but not 853), `system`, or a custom `udp://ip[:port]` / `tcp://ip[:port]` upstream.
+    pub fn from_name(name: &str) -> Result<Resolver, ResolveError> {
+        let (config, opts) = Self::config_from_name(name)?;
+        Ok(Self::with_config(config, opts).named(name.trim()))
+    }
+
+    /// Adds an upstream that is only queried by [`Resolver::lookup_ips_multi`].
+    pub fn with_upstream(mut self, name: &str) -> Result<Resolver, ResolveError> {
+        self.upstreams.push(Self::from_name(name)?);
+        Ok(self)
+    }
+
+    /// Attaches an EDNS Client Subnet option for `net` to every query, so CDNs
+    /// answer as they would for a client inside that network. Only upstreams
+    /// that honor ECS are affected (Google does; Cloudflare and the default
+    /// Quad9 endpoint drop it). Queries made this way bypass the answer cache.
+    pub fn with_client_subnet(mut self, net: IpNet) -> Resolver {
+        let pool = NameServerPool::from_config(
+            self.resolver.config().name_servers().to_vec().into(),
+            Arc::new(self.resolver.options().clone()),
+            TokioConnectionProvider::default(),
+        );
+        self.client_subnet = Some(SubnetClient { subnet: net, pool });
+        self
+    }
+
+    fn named(mut self, name: &str) -> Resolver {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// This resolver or one of its comparison upstreams, looked up by name.
+    pub fn by_name(&self, name: &str) -> Option<&Resolver> {
+        std::iter::once(self).chain(self.upstreams.iter())
+            .find(|resolver| resolver.name == name)
+    }
+
+    /// Drops every cached answer, including those of comparison upstreams.
+    pub fn clear_cache(&self) {
+        self.resolver.clear_cache();
+        self.upstreams.iter().for_each(Resolver::clear_cache);
+    }
+
+    /// Upstream `name` stands for, with the options to query it. The system resolver keeps
+    /// its own options (timeouts, attempts, search domains), only the ones our lookups rely on are overridden.
+    fn config_from_name(name: &str) -> Result<(ResolverConfig, ResolverOpts), ResolveError> {
+        let config = match name.trim().to_lowercase().as_str() {
+            "" | "quad9" => ResolverConfig::quad9_https(),
+            "google" => ResolverConfig::google_https(),
+            "cloudflare" => ResolverConfig::cloudflare_https(),
+            "quad9-tls" => ResolverConfig::quad9_tls(),
+            "google-tls" => ResolverConfig::google_tls(),
+            "cloudflare-tls" => ResolverConfig::cloudflare_tls(),
+            "system" => {
+                let (config, mut opts) = hickory_resolver::system_conf::read_system_conf()
+                    .map_err(|e| ResolveError::InvalidConfig(e.to_string()))?;
+                let defaults = Self::default_opts();
+                opts.ip_strategy = defaults.ip_strategy;
+                opts.cache_size = defaults.cache_size;
+                return Ok((config, opts));
+            }
+            custom => {
+                let url = Url::parse(custom)
+                    .map_err(|e| ResolveError::InvalidConfig(format!("{custom}: {e}")))?;
+                let ip = url.host_str()
+                    .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+                    .and_then(|host| host.parse::<IpAddr>().ok())
+                    .ok_or_else(|| ResolveError::InvalidConfig(format!("{custom}: expected an IP address")))?;
+                let protocol = match url.scheme() {
+                    "udp" => ProtocolConfig::Udp,
+                    "tcp" => ProtocolConfig::Tcp,
+                    scheme => return Err(ResolveError::InvalidConfig(format!("{custom}: unsupported scheme {scheme}"))),
+                };
+                let server = NameServerConfig::new(SocketAddr::new(ip, url.port().unwrap_or(53)), protocol);
+                ResolverConfig::from_parts(None, vec![], vec![server])
+            }
+        };
+        Ok((config, Self::default_opts()))
+    }
+
+    fn default_opts() -> ResolverOpts {
+        let mut opts = ResolverOpts::default();
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        opts.timeout = Duration::from_secs(2);
+        opts.attempts = 2;
+        opts.cache_size = 4096;
+        opts
+    }
+
+    pub async fn resolve(&self, domain: &str) -> Result<Resolution, ResolveError> {
+        if let Some(client) = &self.client_subnet {
+            return client.resolve(domain).await;
+        }
+
+        let lookup = self.resolver.lookup_ip(domain).await
+            .map_err(Self::map_error)?;
+
+        let mut resolution = Resolution { ips: vec![], cname_chain: vec![] };
+        resolution.push_records(lookup.as_lookup().records());
+        Ok(resolution)
+    }
+
+    fn map_error(e: ProtoError) -> ResolveError {
+        match *e.kind {
+            ProtoErrorKind::NoRecordsFound(NoRecords { response_code: ResponseCode::ServFail, .. }) => ResolveError::ServFail,
+            ProtoErrorKind::NoRecordsFound(NoRecords { response_code: ResponseCode::Refused, .. }) => ResolveError::Refused,
+            ProtoErrorKind::NoRecordsFound(_) => ResolveError::NxDomain,
+            ProtoErrorKind::Timeout => ResolveError::Timeout,
+            _ => ResolveError::Other(Error::new(ErrorKind::Other, e)),
+        }
+    }
+
+    /// Mail exchanger hosts of `domain`, most preferred first.
+    pub async fn lookup_mx(&self, domain: &str) -> Result<Vec<String>, ResolveError> {
+        let lookup = self.resolver.mx_lookup(domain).await
+            .map_err(Self::map_error)?;
+        let mut mx: Vec<_> = lookup.iter()
+            .map(|mx| (mx.preference(), Self::host_name(mx.exchange())))
+            .collect();
+        mx.sort();
+        Ok(mx.into_iter().map(|(_, host)| host).collect())
+    }
+
+    /// Authoritative nameserver hosts of `domain`.
+    pub async fn lookup_ns(&self, domain: &str) -> Result<Vec<String>, ResolveError> {
+        let lookup = self.resolver.ns_lookup(domain).await
+            .map_err(Self::map_error)?;
+        Ok(lookup.iter().map(|ns| Self::host_name(&ns.0)).collect())
+    }
+
+    fn host_name(name: &Name) -> String {
+        name.to_utf8().trim_end_matches('.').to_string()
     }
 
     pub async fn lookup_ips(&self, domain: &str) -> Result<Vec<IpAddr>, ResolveError> {
-        Ok(self.resolver.lookup_ip(domain).await
-            .map_err(|e| if e.kind.is_no_records_found() {
-                ResolveError::NxDomain
-            } else {
-                ResolveError::Other(Error::new(ErrorKind::Other, e))
-            })?
-            .into_iter().collect())
+        Ok(self.resolve(domain).await?.ips)
+    }
+
+    /// Resolves `domain` through this resolver and every comparison upstream concurrently.
+    /// Upstreams that fail with anything but NXDOMAIN are left out.
+    pub async fn lookup_ips_multi(&self, domain: &str) -> HashMap<String, Vec<IpAddr>> {
+        let lookups = std::iter::once(self).chain(self.upstreams.iter())
+            .map(|resolver| async move { (resolver.name.clone(), resolver.answer(domain).await) });
+
+        join_all(lookups).await.into_iter()
+            .filter_map(|(name, ips)| Some((name, ips?)))
+            .collect()
+    }
+
+    /// Addresses this resolver returns for `domain`, empty for NXDOMAIN and `None` on failure.
+    async fn answer(&self, domain: &str) -> Option<Vec<IpAddr>> {
+        match self.lookup_ips(domain).await {
+            Ok(ips) => Some(ips),
+            Err(ResolveError::NxDomain) => Some(vec![]),
+            Err(e) => {
+                warn!("{} failed to resolve {}: {}", self.name, domain, e);
+                None
+            }
+        }
+    }
+
+    pub async fn compare(&self, domain: &str) -> DnsComparison {
+        let ips = self.lookup_ips_multi(domain).await;
+        let diverged = Self::diverged(ips.values());
+        DnsComparison { ips, diverged }
+    }
+
+    /// Like [`Resolver::compare`] for an answer `ips` this resolver already gave, only
+    /// querying the comparison upstreams. `None` with fewer than two answer sets to compare.
+    pub async fn diverges_from(&self, domain: &str, ips: &[IpAddr]) -> Option<bool> {
+        let upstreams: Vec<Vec<IpAddr>> = join_all(self.upstreams.iter().map(|upstream| upstream.answer(domain)))
+            .await.into_iter().flatten().collect();
+        if upstreams.is_empty() {
+            return None;
+        }
+        let ips = ips.to_vec();
+        Some(Self::diverged(std::iter::once(&ips).chain(upstreams.iter())))
+    }
+
+    /// Some pair of answers is completely disjoint, not counting two empty ones.
+    fn diverged<'a>(answers: impl Iterator<Item = &'a Vec<IpAddr>>) -> bool {
+        let sets: Vec<HashSet<&IpAddr>> = answers.map(|ips| ips.iter().collect()).collect();
+        sets.iter().enumerate()
+            .any(|(i, a)| sets[i + 1..].iter()
+                .any(|b| a.is_disjoint(b) && !(a.is_empty() && b.is_empty())))
+    }
+}
+
+impl SubnetClient {
+    async fn resolve(&self, domain: &str) -> Result<Resolution, ResolveError> {
+        let name = Name::from_str(domain)
+            .map_err(|e| ResolveError::Other(Error::new(ErrorKind::InvalidInput, e)))?;
+
+        let mut resolution = Resolution { ips: vec![], cname_chain: vec![] };
+        let mut error = None;
+        for record_type in [RecordType::A, RecordType::AAAA] {
+            match self.pool.send(self.request(name.clone(), record_type)).next().await {
+                Some(Ok(response)) => match response.response_code() {
+                    ResponseCode::NoError => resolution.push_records(response.answers()),
+                    code => error = Some(Self::map_response_code(code)),
+                },
+                Some(Err(e)) => error = Some(Resolver::map_error(e)),
+                None => {}
+            }
+        }
+
+        match error {
+            _ if !resolution.ips.is_empty() => Ok(resolution),
+            Some(e) => Err(e),
+            None => Err(ResolveError::NxDomain),
+        }
+    }
+
+    /// Same rules as [`Resolver::map_error`] for an error code in a response.
+    fn map_response_code(code: ResponseCode) -> ResolveError {
+        match code {
+            ResponseCode::NXDomain => ResolveError::NxDomain,
+            ResponseCode::ServFail => ResolveError::ServFail,
+            ResponseCode::Refused => ResolveError::Refused,
+            code => ResolveError::Other(Error::other(code.to_string())),
+        }
+    }
+
+    fn request(&self, name: Name, record_type: RecordType) -> DnsRequest {
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232).set_version(0);
+        edns.options_mut().insert(EdnsOption::Subnet(ClientSubnet::from(self.subnet)));
+
+        let mut message = Message::query();
+        message
+            .add_query(Query::query(name, record_type))
+            .set_recursion_desired(true)
+            .set_edns(edns);
+        DnsRequest::new(message, DnsRequestOptions::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::rr::rdata::opt::EdnsCode;
+
+    #[tokio::test]
+    #[ignore = "needs network access"]
+    async fn cname_chain() {
+        let resolution = Resolver::new().await.resolve("www.github.com").await.unwrap();
+        assert_eq!(resolution.cname_chain, vec!["github.com".to_string()]);
+        assert!(!resolution.ips.is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_subnet_option() {
+        let subnet: IpNet = "198.51.100.0/24".parse().unwrap();
+        let resolver = Resolver::from_name("udp://127.0.0.1").unwrap().with_client_subnet(subnet);
+        let request = resolver.client_subnet.as_ref().unwrap()
+            .request(Name::from_str("example.com.").unwrap(), RecordType::A);
+
+        let message = Message::from_vec(&request.to_vec().unwrap()).unwrap();
+        let edns = message.extensions().as_ref().expect("OPT record");
+        assert_eq!(edns.option(EdnsCode::Subnet), Some(&EdnsOption::Subnet(ClientSubnet::from(subnet))));
     }
 }
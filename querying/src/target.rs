@@ -1,21 +1,31 @@
-use crate::resolver::{ResolveError, Resolver};
+use crate::resolver::{ResolveError, Resolution, Resolver};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use url::Url;
+use thiserror::Error;
+use url::{Host, Url};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Target {
     Domain(String),
     Ipv4(Ipv4Addr),
     Ipv6(Ipv6Addr),
 }
 
+#[derive(Error, Debug)]
+pub enum TargetError {
+    #[error("not a valid domain: {0}")]
+    Invalid(String),
+}
+
 impl From<&str> for Target {
     fn from(input: &str) -> Self {
         if let Ok(ipv4) = input.parse::<Ipv4Addr>() {
             return Target::Ipv4(ipv4);
         }
 
-        if let Ok(ipv6) = input.parse::<Ipv6Addr>() {
+        // `[2001:db8::1]` as in URLs, and `fe80::1%eth0` with a zone that Ipv6Addr can't hold
+        let ipv6 = input.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(input);
+        let ipv6 = ipv6.split_once('%').map_or(ipv6, |(addr, _zone)| addr);
+        if let Ok(ipv6) = ipv6.parse::<Ipv6Addr>() {
             return Target::Ipv6(ipv6);
         }
 
@@ -28,20 +38,74 @@ impl From<&str> for Target {
     }
 }
 
+/// Target along with what the user actually typed, e.g. a full URL with a path,
+/// for echoing it back. Only the target takes part in checks and comparisons.
+#[derive(Debug, Clone)]
+pub struct TargetInput {
+    original: String,
+    target: Target,
+}
+
+impl From<&str> for TargetInput {
+    fn from(input: &str) -> Self {
+        TargetInput { original: input.to_string(), target: Target::from(input) }
+    }
+}
+
+impl TargetInput {
+    pub fn original_input(&self) -> &str {
+        &self.original
+    }
+
+    /// The original input when it says more than the target itself, like a scheme or a path.
+    pub fn differing_input(&self) -> Option<&str> {
+        (self.original != self.target.to_query()).then_some(self.original.as_str())
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    pub fn into_target(self) -> Target {
+        self.target
+    }
+}
+
 impl Target {
+    /// Stable key of the target kind, localized by the website's `target_type` bundle entries.
     pub fn readable_type(&self) -> &'static str {
         match self {
-            Target::Domain(_) => "Домен",
-            Target::Ipv4(_) => "IPv4-адрес",
-            Target::Ipv6(_) => "IPv6-адрес"
+            Target::Domain(_) => "domain",
+            Target::Ipv4(_) => "ipv4",
+            Target::Ipv6(_) => "ipv6"
+        }
+    }
+
+    /// Checks a domain against the RFC 1035 label rules after IDN conversion,
+    /// so sentences or paths pasted as targets don't reach the resolver.
+    pub fn validate(&self) -> Result<(), TargetError> {
+        let Target::Domain(domain) = self else {
+            return Ok(());
+        };
+        let invalid = || TargetError::Invalid(domain.clone());
+        let Ok(Host::Domain(ascii)) = Host::parse(domain.trim_end_matches('.')) else {
+            return Err(invalid());
+        };
+        let labels: Vec<&str> = ascii.split('.').collect();
+        let valid_label = |label: &&str| (1..=63).contains(&label.len())
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if ascii.len() > 253 || labels.len() < 2 || !labels.iter().all(valid_label) {
+            return Err(invalid());
         }
+        Ok(())
     }
 
-    pub async fn resolve(&self, resolver: &Resolver) -> Result<Vec<IpAddr>, ResolveError> {
+    pub async fn resolve(&self, resolver: &Resolver) -> Result<Resolution, ResolveError> {
         Ok(match self {
-            Target::Domain(domain) => resolver.lookup_ips(domain).await?,
-            Target::Ipv4(ipv4) => vec![IpAddr::V4(ipv4.clone())],
-            Target::Ipv6(ipv6) => vec![IpAddr::V6(ipv6.clone())],
+            Target::Domain(domain) => resolver.resolve(domain).await?,
+            Target::Ipv4(ipv4) => Resolution { ips: vec![IpAddr::V4(ipv4.clone())], cname_chain: vec![] },
+            Target::Ipv6(ipv6) => Resolution { ips: vec![IpAddr::V6(ipv6.clone())], cname_chain: vec![] },
         })
     }
 
@@ -53,3 +117,18 @@ impl Target {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bracketed_ipv6() {
+        assert_eq!(Target::from("[2001:db8::1]"), Target::Ipv6("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_with_zone() {
+        assert_eq!(Target::from("fe80::1%eth0"), Target::Ipv6("fe80::1".parse().unwrap()));
+    }
+}
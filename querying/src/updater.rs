@@ -1,47 +1,86 @@
 use async_trait::async_trait;
-use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
-use log::info;
-use reqwest::IntoUrl;
-use std::fmt::Display;
-use std::io;
+use maxminddb::MaxMindDbError;
+use serde::Serialize;
 use std::io::Error;
+use thiserror::Error;
+use tokio::sync::watch;
 
-pub async fn fetch_db<T: IntoUrl + Display>(url: T) -> Result<Vec<u8>, Error> {
-    info!("Fetching {}", url);
-    let response = reqwest::get(url).await
-        .map_err(|e| Error::new(io::ErrorKind::Other, e))?
-        .error_for_status()
-        .map_err(|e| Error::new(io::ErrorKind::Other, e))?;
-
-    let total_size = response.content_length().unwrap_or(0);
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .map_err(|e| Error::new(io::ErrorKind::Other, e))?
-        .progress_chars("#>-"));
-
-    let mut bytes = Vec::new();
-    bytes.reserve(total_size as usize);
-    let mut stream = response.bytes_stream();
-
-    while let Some(chunk_result) = stream.next().await {
-        let chunk = chunk_result.map_err(|e| Error::new(io::ErrorKind::Other, e))?;
-        bytes.extend(&chunk);
-        pb.inc(chunk.len() as u64);
+#[cfg(feature = "network")]
+pub use crate::fetch::fetch_db;
+
+/// State of a single database within a refresh.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DbStatus {
+    #[default]
+    Pending,
+    /// `total` is 0 until the server reports a content length
+    Downloading { downloaded: u64, total: u64 },
+    Parsing,
+    Installed,
+    Failed { error: String },
+}
+
+/// Live status of every database, driven by `Checker::update_all`.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct UpdateStatus {
+    pub geo_ip: DbStatus,
+    pub rkn: DbStatus,
+    pub cdn: DbStatus,
+}
+
+/// Reports the status of one database into a shared [`UpdateStatus`].
+pub struct Progress {
+    tx: watch::Sender<UpdateStatus>,
+    field: fn(&mut UpdateStatus) -> &mut DbStatus,
+}
+
+impl Progress {
+    pub fn new(tx: watch::Sender<UpdateStatus>, field: fn(&mut UpdateStatus) -> &mut DbStatus) -> Progress {
+        Progress { tx, field }
+    }
+
+    pub fn set(&self, status: DbStatus) {
+        self.tx.send_modify(|s| *(self.field)(s) = status);
     }
 
-    pb.finish_with_message("Download complete!");
+    /// Adds to the byte counters; databases made of several files add up.
+    #[cfg(feature = "network")]
+    pub(crate) fn advance(&self, downloaded: u64, total: u64) {
+        self.tx.send_modify(|s| match (self.field)(s) {
+            DbStatus::Downloading { downloaded: d, total: t } => {
+                *d += downloaded;
+                *t += total;
+            }
+            status => *status = DbStatus::Downloading { downloaded, total },
+        });
+    }
+}
 
-    Ok(bytes)
+/// Why a downloaded database was not installed. The previously installed data is kept.
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error(transparent)]
+    Io(#[from] Error),
+    #[error(transparent)]
+    MaxMind(#[from] MaxMindDbError),
+    #[error("validation failed: {0}")]
+    Invalid(String),
 }
 
 #[async_trait]
 pub trait Updatable {
     type Base;
-    async fn download() -> Result<Self::Base, Error>;
-    async fn install(&mut self, base: Self::Base) -> Result<(), Error>;
+    #[cfg(feature = "network")]
+    async fn download(progress: &Progress) -> Result<Self::Base, Error>;
+    /// Parses and validates `base`, replacing the current data only if both succeed.
+    async fn install(&mut self, base: Self::Base) -> Result<(), UpdateError>;
+    #[cfg(feature = "network")]
     fn get_url(key: &'static str, default: &'static str) -> String {
         std::env::var(key).ok().unwrap_or(default.to_string())
     }
+    /// Smallest record count a download may have to be installed, overridable with `key`.
+    fn min_records(key: &'static str, default: usize) -> usize {
+        std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
 }
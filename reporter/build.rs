@@ -3,11 +3,6 @@ use reqwest::blocking::Client;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
-    let junk_path = Path::new(&out_dir).join("junk.bin");
-
-    let mut data = vec![0u8; 64 * 1024];
-    getrandom::fill(&mut data).expect("Failed to generate random bytes");
-    fs::write(&junk_path, &data).expect("Failed to write random data");
 
     let client = Client::new();
     println!("cargo:rerun-if-env-changed=DIST_DOMAIN_COUNT");
@@ -1,66 +1,384 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
 use std::path::PathBuf;
-use log::info;
+use clap::ValueEnum;
+use tracing::info;
 use reports::Evidence;
+use serde::Serialize;
 use crate::Verbosity;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    /// Single `{target: evidence}` object, same as the uploaded report
+    Json,
+    /// One `{"target": ..., "evidence": ...}` object per line
+    Ndjson,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    target: &'a str,
+    #[serde(flatten)]
+    result: &'a ProbeResult,
+}
+
+/// Outcome of probing a single target. Timings are zero for errors
+/// and for results carried over from a previous run.
+#[derive(Debug, Serialize)]
+pub struct ProbeResult {
+    pub evidence: Evidence,
+    pub duration_ms: u64,
+    pub bytes: usize,
+}
+
+impl ProbeResult {
+    fn to_record(&self) -> [String; 3] {
+        [self.evidence.to_string(), self.duration_ms.to_string(), self.bytes.to_string()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub ok: usize,
+    pub blocked: usize,
+    /// Blocks detected at the connection stage, `early_confirmed + early_unconfirmed`
+    pub early: usize,
+    /// Early blocks that held up when re-probed with `--confirm-blocks`
+    pub early_confirmed: usize,
+    /// Early blocks that were not re-probed
+    pub early_unconfirmed: usize,
+    pub error: usize,
+    pub total: usize,
+    pub elapsed_secs: u64,
+}
+
+const HEADER: [&str; 4] = ["target", "evidence", "duration_ms", "bytes"];
+
+/// Registrable domain (eTLD+1) of `target`, or the target itself when it has none, like an IP.
+fn site(target: &str) -> &str {
+    if target.parse::<IpAddr>().is_ok() {
+        return target;
+    }
+    psl::domain_str(target).unwrap_or(target)
+}
+
 #[derive(Default)]
 pub struct Counter {
     ok: usize,
     block: usize,
     err: usize,
-    pub early: usize,
-    pub results: HashMap<String, Evidence>,
+    early_confirmed: usize,
+    early_unconfirmed: usize,
+    pub results: HashMap<String, ProbeResult>,
+    /// Category of each target, when the target list provides one
+    pub categories: HashMap<String, String>,
+    /// Whether each of the last `error_window` results was an error, see [`Counter::track_error_rate`]
+    recent_errors: VecDeque<bool>,
+    error_window: usize,
 }
 
 impl Counter {
-    pub fn save_results(&self, output: &PathBuf) -> anyhow::Result<()> {
-        let mut out = csv::WriterBuilder::new().from_path(output)?;
-        out.write_record(&["target", "evidence"])?;
-        for (target, evidence) in &self.results {
-            out.write_record(&[target, &evidence.to_string()])?;
+    pub fn save_results(&self, output: &PathBuf, format: OutputFormat) -> anyhow::Result<()> {
+        match format {
+            OutputFormat::Csv => {
+                let mut out = csv::WriterBuilder::new().from_path(output)?;
+                out.write_record(HEADER)?;
+                for (target, result) in &self.results {
+                    let [evidence, duration_ms, bytes] = result.to_record();
+                    out.write_record([target, &evidence, &duration_ms, &bytes])?;
+                }
+            }
+            OutputFormat::Json => {
+                let mut out = BufWriter::new(File::create(output)?);
+                let evidence: HashMap<&String, &Evidence> = self.results.iter()
+                    .map(|(target, result)| (target, &result.evidence))
+                    .collect();
+                serde_json::to_writer(&mut out, &evidence)?;
+                out.flush()?;
+            }
+            OutputFormat::Ndjson => {
+                let mut out = BufWriter::new(File::create(output)?);
+                for (target, result) in &self.results {
+                    serde_json::to_writer(&mut out, &Record { target, result })?;
+                    out.write_all(b"\n")?;
+                }
+                out.flush()?;
+            }
         }
         info!("Saved results to {:?}", output);
         Ok(())
     }
 
-    pub fn print_results(&self, verbosity: &Verbosity) {
+    /// Reads a results CSV; timing columns are optional so older files still load.
+    pub fn load_results(input: &PathBuf) -> anyhow::Result<HashMap<String, ProbeResult>> {
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).from_path(input)?;
+        let mut results = HashMap::new();
+        for record in rdr.records() {
+            let record = record?;
+            if let (Some(target), Some(evidence)) = (record.get(0), record.get(1)) {
+                results.insert(target.to_string(), ProbeResult {
+                    evidence: evidence.parse().map_err(anyhow::Error::msg)?,
+                    duration_ms: record.get(2).and_then(|v| v.parse().ok()).unwrap_or(0),
+                    bytes: record.get(3).and_then(|v| v.parse().ok()).unwrap_or(0),
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    /// Targets probed both now and in `baseline` whose evidence changed,
+    /// as `(target, baseline, current)` sorted by target.
+    pub fn diff(&self, baseline: &HashMap<String, Evidence>) -> Vec<(String, Evidence, Evidence)> {
+        let mut changes: Vec<_> = self.results.iter()
+            .filter_map(|(target, result)| {
+                let before = baseline.get(target).filter(|before| **before != result.evidence)?;
+                Some((target.clone(), before.clone(), result.evidence.clone()))
+            })
+            .collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+
+    /// Writes the output of [`Counter::diff`] as a `target,baseline,evidence` CSV.
+    pub fn save_diff(changes: &[(String, Evidence, Evidence)], output: &PathBuf) -> anyhow::Result<()> {
+        let mut out = csv::WriterBuilder::new().from_path(output)?;
+        out.write_record(["target", "baseline", "evidence"])?;
+        for (target, before, after) in changes {
+            out.write_record([target, &before.to_string(), &after.to_string()])?;
+        }
+        out.flush()?;
+        info!("Saved {} changes to {:?}", changes.len(), output);
+        Ok(())
+    }
+
+    /// Takes over conclusive results of a previous run, returning how many were kept.
+    pub fn resume(&mut self, results: HashMap<String, ProbeResult>) -> usize {
+        let mut resumed = 0;
+        for (target, result) in results {
+            if result.evidence.is_conclusive() {
+                self.add(&target, result);
+                resumed += 1;
+            }
+        }
+        resumed
+    }
+
+    /// Prints results at the given verbosity, blocks per category when the target list
+    /// had categories, and with `group_by_site` blocks per registrable domain.
+    pub fn print_results(&self, verbosity: &Verbosity, group_by_site: bool) {
         if verbosity > &Verbosity::Silent {
             info!("Results:");
-            for (target, evidence) in &self.results {
-                match evidence {
+            for (target, result) in &self.results {
+                match result.evidence {
                     Evidence::Ok if verbosity >= &Verbosity::All => println!("    [Ok] {}", target),
                     Evidence::Blocked if verbosity >= &Verbosity::Block => println!("    [Blocked] {}", target),
+                    Evidence::Timeout if verbosity >= &Verbosity::Block => println!("    [Timeout] {}", target),
+                    Evidence::Reset if verbosity >= &Verbosity::Block => println!("    [Reset] {}", target),
+                    Evidence::QuicBlocked if verbosity >= &Verbosity::Block => println!("    [QuicBlocked] {}", target),
+                    Evidence::LegalBlock if verbosity >= &Verbosity::Block => println!("    [LegalBlock] {}", target),
                     Evidence::ConnectError if verbosity >= &Verbosity::Error => println!("    [ConnectError] {}", target),
                     _ => {}
                 }
             }
         }
+
+        if !self.categories.is_empty() {
+            let mut blocked: BTreeMap<&str, usize> = BTreeMap::new();
+            for (target, result) in &self.results {
+                if result.evidence.is_block() {
+                    let category = self.categories.get(target).map_or("uncategorized", String::as_str);
+                    *blocked.entry(category).or_default() += 1;
+                }
+            }
+            info!("Blocked by category:");
+            for (category, count) in blocked {
+                println!("    {}: {}", category, count);
+            }
+        }
+
+        if group_by_site {
+            let mut sites: HashMap<&str, (usize, usize)> = HashMap::new();
+            for (target, result) in &self.results {
+                let (blocked, total) = sites.entry(site(target)).or_default();
+                *total += 1;
+                if result.evidence.is_block() {
+                    *blocked += 1;
+                }
+            }
+            let mut sites: Vec<_> = sites.into_iter()
+                .filter(|(_, (blocked, _))| *blocked > 0)
+                .collect();
+            sites.sort_by(|a, b| b.1.0.cmp(&a.1.0).then(a.0.cmp(b.0)));
+            info!("Blocked by site:");
+            for (site, (blocked, total)) in sites {
+                println!("    {}: {}/{} subdomains blocked", site, blocked, total);
+            }
+        }
     }
     pub fn total(&self) -> usize {
         self.ok + self.block + self.err
     }
 
-    pub fn add(&mut self, target: &str, evidence: Evidence) {
+    pub fn summary(&self, elapsed_secs: u64) -> Summary {
+        Summary {
+            ok: self.ok,
+            blocked: self.block,
+            early: self.early_confirmed + self.early_unconfirmed,
+            early_confirmed: self.early_confirmed,
+            early_unconfirmed: self.early_unconfirmed,
+            error: self.err,
+            total: self.total(),
+            elapsed_secs,
+        }
+    }
+
+    /// Counts an early block, `confirmed` once it was seen again on a re-probe.
+    pub fn add_early(&mut self, confirmed: bool) {
+        if confirmed {
+            self.early_confirmed += 1;
+        } else {
+            self.early_unconfirmed += 1;
+        }
+    }
+
+    /// Starts keeping the error share of the last `window` results, for [`Counter::recent_error_rate`].
+    pub fn track_error_rate(&mut self, window: usize) {
+        self.error_window = window;
+        self.recent_errors = VecDeque::with_capacity(window + 1);
+    }
+
+    /// Share of errors among the last results, `None` until a full window was seen.
+    pub fn recent_error_rate(&self) -> Option<f64> {
+        if self.error_window == 0 || self.recent_errors.len() < self.error_window {
+            return None;
+        }
+        Some(self.recent_errors.iter().filter(|error| **error).count() as f64 / self.error_window as f64)
+    }
+
+    pub fn add(&mut self, target: &str, result: ProbeResult) {
+        if self.error_window > 0 {
+            self.recent_errors.push_back(matches!(result.evidence, Evidence::ConnectError | Evidence::Error));
+            if self.recent_errors.len() > self.error_window {
+                self.recent_errors.pop_front();
+            }
+        }
+        *self.tally(&result.evidence) += 1;
+        self.results.insert(target.to_string(), result);
+    }
+
+    /// Builds a counter from a results file, see [`Counter::load_results`].
+    pub fn from_results(input: &PathBuf) -> anyhow::Result<Counter> {
+        let mut counter = Counter::default();
+        for (target, result) in Self::load_results(input)? {
+            counter.add(&target, result);
+        }
+        Ok(counter)
+    }
+
+    /// Combines results of another vantage point. A target seen by both keeps
+    /// the stronger evidence: a block wins over ok, which wins over an error.
+    pub fn merge(&mut self, other: Counter) {
+        self.early_confirmed += other.early_confirmed;
+        self.early_unconfirmed += other.early_unconfirmed;
+        self.categories.extend(other.categories);
+        for (target, result) in other.results {
+            match self.results.remove(&target) {
+                Some(existing) if precedence(&existing.evidence) >= precedence(&result.evidence) => {
+                    self.results.insert(target, existing);
+                }
+                Some(existing) => {
+                    *self.tally(&existing.evidence) -= 1;
+                    self.add(&target, result);
+                }
+                None => self.add(&target, result),
+            }
+        }
+    }
+
+    fn tally(&mut self, evidence: &Evidence) -> &mut usize {
         match evidence {
-            Evidence::Ok => self.ok += 1,
-            Evidence::Blocked => self.block += 1,
-            Evidence::ConnectError | Evidence::Error => self.err += 1,
+            Evidence::Ok => &mut self.ok,
+            Evidence::Blocked | Evidence::Timeout | Evidence::Reset | Evidence::QuicBlocked | Evidence::LegalBlock => &mut self.block,
+            Evidence::ConnectError | Evidence::Error => &mut self.err,
         }
-        self.results.insert(target.to_string(), evidence);
     }
 }
 
+fn precedence(evidence: &Evidence) -> u8 {
+    match evidence {
+        e if e.is_block() => 2,
+        Evidence::Ok => 1,
+        _ => 0,
+    }
+}
+
+/// Share of `count` in `total`, 0 for empty runs rather than NaN.
+fn percent(count: usize, total: usize) -> f32 {
+    if total == 0 {
+        return 0.0;
+    }
+    count as f32 / total as f32 * 100.0
+}
+
 impl Display for Counter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let total = self.total();
-        write!(f, "OK {} ({:.2}%) | Blocked {} (early: {}) ({:.2}%) | Error {} ({:.2}%)",
-               self.ok,
-               self.ok as f32 / total as f32 * 100.0,
-               self.block, self.early,
-               self.block as f32 / total as f32 * 100.0,
-               self.err,
-               self.err as f32 / total as f32 * 100.0)
+        write!(f, "OK {} ({:.2}%) | Blocked {} ({:.2}%, early: {} confirmed, {} unconfirmed) | Error {} ({:.2}%)",
+               self.ok, percent(self.ok, total),
+               self.block, percent(self.block, total),
+               self.early_confirmed, self.early_unconfirmed,
+               self.err, percent(self.err, total))
+    }
+}
+
+/// Appends results to the output CSV as they arrive.
+pub struct StreamWriter {
+    out: csv::Writer<File>,
+    pending: usize,
+    flush_every: usize,
+}
+
+impl StreamWriter {
+    pub fn open(output: &PathBuf, flush_every: usize) -> anyhow::Result<StreamWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(output)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut out = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+        if empty {
+            out.write_record(HEADER)?;
+        }
+        Ok(StreamWriter { out, pending: 0, flush_every: flush_every.max(1) })
+    }
+
+    pub fn write(&mut self, target: &str, result: &ProbeResult) -> anyhow::Result<()> {
+        let [evidence, duration_ms, bytes] = result.to_record();
+        self.out.write_record([target, &evidence, &duration_ms, &bytes])?;
+        self.pending += 1;
+        if self.pending >= self.flush_every {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_counter_display() {
+        assert_eq!(
+            Counter::default().to_string(),
+            "OK 0 (0.00%) | Blocked 0 (0.00%, early: 0 confirmed, 0 unconfirmed) | Error 0 (0.00%)"
+        );
     }
 }
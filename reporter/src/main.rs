@@ -1,27 +1,34 @@
 mod resolver;
 mod counter;
+mod quic;
 
 use crate::resolver::Resolver;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use clap::{Parser, ValueEnum};
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
 use indicatif::{ProgressIterator, ProgressStyle};
-use log::{error, info, warn, LevelFilter};
-use reports::{AgencyReport, Evidence, ReporterConfig};
+use reports::{AgencyReport, Evidence, Protocol, ReporterConfig, SCHEMA};
+use reqwest::header::{HeaderName, HeaderValue};
 use reqwest::redirect::Policy;
-use reqwest::Client;
-use serde::Serialize;
+use reqwest::{Client, Method, Proxy};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::error::Error;
 use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::task::{JoinError, JoinSet};
 use tokio::time::Instant;
-use counter::Counter;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, field, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+use counter::{Counter, OutputFormat, ProbeResult, StreamWriter};
 
-const JUNK: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/junk.bin"));
+static JUNK: OnceLock<Vec<u8>> = OnceLock::new();
 
 #[derive(Serialize, Debug, Ord, PartialOrd, Eq, PartialEq, Clone, ValueEnum)]
 #[serde(rename_all = "kebab-case")]
@@ -32,6 +39,45 @@ enum Verbosity {
     All,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HttpMethod {
+    Get,
+    Head,
+}
+
+impl From<HttpMethod> for Method {
+    fn from(method: HttpMethod) -> Method {
+        match method {
+            HttpMethod::Get => Method::GET,
+            HttpMethod::Head => Method::HEAD,
+        }
+    }
+}
+
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header.split_once(':')
+        .ok_or_else(|| format!("expected \"Name: value\", got {header:?}"))?;
+    Ok((
+        name.trim().parse().map_err(|e| format!("invalid header name {name:?}: {e}"))?,
+        value.trim().parse().map_err(|e| format!("invalid header value {value:?}: {e}"))?,
+    ))
+}
+
+/// Parses durations like `90s`, `30m` or `2h`; a bare number is in seconds.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let split = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: u64 = value.parse().map_err(|_| format!("invalid duration {duration:?}"))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("unknown unit {unit:?} in {duration:?}, expected s, m or h")),
+    };
+    Ok(Duration::from_secs(value * scale))
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about = "DPI probe: checks blockage of domains by SNI")]
 struct Args {
@@ -44,6 +90,10 @@ struct Args {
     #[arg(short, long, required = false)]
     fake: Option<String>,
 
+    /// Target list CSV with `rank,domain,category` columns, instead of the built-in list
+    #[arg(long, required = false)]
+    targets: Option<PathBuf>,
+
     /// Take first N targets
     #[arg(short, long, default_value_t = 100_000)]
     count: usize,
@@ -52,31 +102,94 @@ struct Args {
     #[arg(short, long, default_value_t = 5)]
     timeout_secs: u64,
 
-    /// Maximum concurrent probes. Make sure that it doesn't exceed 'ulimit -n'
+    /// Maximum concurrent probes. Reduced to fit 'ulimit -n' unless --no-clamp is given
     #[arg(short, long = "probes", default_value_t = 1000)]
     probe_count: usize,
 
+    /// Keep the requested number of probes even if it exceeds the open file limit
+    #[arg(long, default_value_t = false)]
+    no_clamp: bool,
+
     /// Display probing results in console
     #[arg(short, long, default_value_t = Verbosity::Silent, value_enum)]
     verbosity: Verbosity,
 
+    /// Summarize blocked targets by registrable domain (eTLD+1) after the run
+    #[arg(long, default_value_t = false)]
+    group_by_site: bool,
+
     /// Attempts to establish connection
     #[arg(short, long, default_value_t = 2)]
     retry_count: usize,
 
+    /// Delay before the first retry in milliseconds, doubled on every next one (with jitter)
+    #[arg(long, default_value_t = 0)]
+    retry_backoff_ms: u64,
+
     /// Try using plain HTTP without TLS
     #[arg(short = 'H', long, default_value_t = false)]
     http: bool,
 
-    /// Send 64kb junk to server
+    /// Send junk to server
     #[arg(short = 'x', long, default_value_t = false)]
     tx: bool,
 
-    /// Target IP to probe with.
-    /// It should be included in IP-ranges of interest.
+    /// Generate the junk payload from this seed instead of randomly, to reproduce a run.
+    #[arg(long, required = false)]
+    seed: Option<u64>,
+
+    /// Size of the junk payload and of the response expected from the server, in bytes
+    #[arg(long, default_value_t = 64 * 1024)]
+    junk_size: usize,
+
+    /// Target IPs to probe with, comma-separated. Requests are spread across them round-robin.
+    /// They should be included in IP-ranges of interest.
     /// The server must respond to any SNI/Host with a response larger than 64kb.
-    #[arg(short, long, default_value = "5.78.7.195", value_parser = |v: &str| v.parse::<IpAddr>())]
-    ip: IpAddr,
+    #[arg(short, long, default_value = "5.78.7.195", value_delimiter = ',', value_parser = |v: &str| v.parse::<IpAddr>())]
+    ip: Vec<IpAddr>,
+
+    /// HTTP version to probe with: auto, h1, h2 or h3.
+    /// h3 requires building with the `http3` feature
+    #[arg(long, default_value = "auto", value_parser = |v: &str| v.parse::<Protocol>())]
+    protocol: Protocol,
+
+    /// TLS SNI to send instead of the target. The Host header keeps the target unless --host is given.
+    /// Not supported with --proxy
+    #[arg(long, required = false)]
+    sni: Option<String>,
+
+    /// HTTP Host header to send instead of the target
+    #[arg(long, required = false)]
+    host: Option<String>,
+
+    /// HTTP method to probe with. HEAD responses have no body,
+    /// so they are judged by status and connection behavior only
+    #[arg(long, default_value_t = HttpMethod::Get, value_enum, ignore_case = true)]
+    method: HttpMethod,
+
+    /// Extra request header, e.g. "User-Agent: curl/8.0". Can be repeated
+    #[arg(long = "header", value_name = "K: V", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Response statuses that are block pages rather than failures, comma-separated.
+    /// They are reported as legal_block without retrying
+    #[arg(long, default_value = "451", value_delimiter = ',')]
+    block_status: Vec<u16>,
+
+    /// Follow up to N redirects instead of treating them as non-OK responses.
+    /// The probe IPs stay pinned, so redirects to other hosts still hit the same IP
+    #[arg(long, default_value_t = 0)]
+    follow_redirects: usize,
+
+    /// Route probes through a proxy, e.g. socks5h://127.0.0.1:1080.
+    /// The proxy connects to targets itself, so --ip is not used
+    #[arg(long, required = false)]
+    proxy: Option<String>,
+
+    /// Only attempt a QUIC handshake with the SNI on UDP port 443, without any HTTP request,
+    /// to tell QUIC-specific drops apart from application-level blocks
+    #[arg(long, default_value_t = false)]
+    quic_probe: bool,
 
     /// File name on the server to test
     #[arg(short = 'P', long, default_value = "100MB.bin")]
@@ -92,6 +205,79 @@ struct Args {
     #[arg(short, long, env = "AGENCY_KEY")]
     key: Option<String>,
 
+    /// Append each result to the output file as it arrives.
+    /// Targets already present in the file are skipped, so an interrupted run can be restarted
+    #[arg(long, default_value_t = false, requires = "output")]
+    stream_output: bool,
+
+    /// Resume from a previous results file: targets that ended up OK or blocked are skipped,
+    /// errors are probed again. Old and new results are uploaded together
+    #[arg(long, required = false)]
+    resume: Option<PathBuf>,
+
+    /// Probe connect-stage blocks once more with a doubled timeout at the end
+    /// and keep only the ones that are blocked again
+    #[arg(long, default_value_t = false)]
+    confirm_blocks: bool,
+
+    /// Flush streamed results to disk every N records
+    #[arg(long, default_value_t = 1000)]
+    flush_every: usize,
+
+    /// Stop probing new targets after this long (e.g. 30m, 2h) and upload what was collected,
+    /// like on Ctrl-C
+    #[arg(long, required = false, value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Abort without uploading once this share (0-1) of the last --error-rate-window results
+    /// are errors, as happens when the probe endpoint goes down mid-run
+    #[arg(long, required = false)]
+    abort_on_error_rate: Option<f64>,
+
+    /// Number of latest results --abort-on-error-rate looks at, also the warm-up before it kicks in
+    #[arg(long, default_value_t = 500)]
+    error_rate_window: usize,
+
+    /// Print a one-line JSON summary to stdout after the run
+    #[arg(long, default_value_t = false)]
+    summary_json: bool,
+
+    /// Don't check that every probe IP serves a full response for --path before the run
+    #[arg(long, default_value_t = false)]
+    skip_preflight: bool,
+
+    /// Print the effective config and number of targets, then exit without probing or uploading
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Upload results in chunks of N entries
+    #[arg(long, default_value_t = 100_000)]
+    upload_batch: usize,
+
+    /// Compress uploaded reports with zstd
+    #[arg(long, default_value_t = false)]
+    compress: bool,
+
+    /// Output results file format
+    #[arg(long, default_value_t = OutputFormat::Csv, value_enum)]
+    format: OutputFormat,
+
+    /// Results CSV of an earlier run to compare against: targets whose evidence changed are printed after probing
+    #[arg(long, required = false)]
+    baseline: Option<PathBuf>,
+
+    /// Save the changes against --baseline to this CSV
+    #[arg(long, required = false, requires = "baseline")]
+    diff_output: Option<PathBuf>,
+
+    /// ASN of the first probe IP, looked up at startup
+    #[arg(skip)]
+    probe_asn: Option<String>,
+
+    /// Combine results files from several vantage points into the output file instead of probing.
+    /// A target blocked in any of them counts as blocked
+    #[arg(long, num_args = 1.., value_name = "FILE")]
+    merge: Vec<PathBuf>,
 }
 
 impl Args {
@@ -99,7 +285,13 @@ impl Args {
         ReporterConfig {
             http: self.http,
             tx_junk: self.tx,
-            ip: self.ip.clone(),
+            ip: self.ip[0],
+            probe_ip_count: self.ip.len(),
+            protocol: self.protocol,
+            proxied: self.proxy.is_some(),
+            follow_redirects: self.follow_redirects,
+            seed: self.seed,
+            probe_asn: self.probe_asn.clone(),
             path: self.path.clone(),
             retry_count: self.retry_count,
             timeout_secs: self.timeout_secs,
@@ -108,42 +300,243 @@ impl Args {
     }
 }
 
-fn build_client(args: &Args, attempt: usize) -> reqwest::Result<Client> {
+fn build_client(args: &Args, resolver: &Arc<Resolver>, attempt: usize) -> reqwest::Result<Client> {
     let client = Client::builder()
         .danger_accept_invalid_certs(true)
-        .redirect(Policy::none())
+        .redirect(match args.follow_redirects {
+            0 => Policy::none(),
+            n => Policy::limited(n),
+        })
         .use_rustls_tls()
-        .dns_resolver(Arc::new(Resolver::new(args.ip)))
         .read_timeout(Duration::from_secs(args.timeout_secs * attempt as u64))
         .timeout(Duration::from_secs(15));
 
+    let client = match &args.proxy {
+        Some(proxy) => client.proxy(Proxy::all(proxy)?),
+        None => client.dns_resolver(resolver.clone()),
+    };
+
+    let client = match args.protocol {
+        Protocol::Auto => client,
+        Protocol::H1 => client.http1_only(),
+        Protocol::H2 => client.http2_prior_knowledge(),
+        #[cfg(feature = "http3")]
+        Protocol::H3 => client.http3_prior_knowledge(),
+        #[cfg(not(feature = "http3"))]
+        Protocol::H3 => unreachable!("h3 is rejected at startup without the http3 feature"),
+    };
+
     Ok(client.build()?)
 }
 
+/// Reads up to `count` targets from a `rank,domain,category` CSV. The category column is optional
+/// and rows with a non-numeric rank (such as a header) are skipped.
+fn load_targets(path: &PathBuf, count: usize) -> Result<Vec<(String, Option<String>)>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_path(path)?;
+    let mut targets = vec![];
+    for record in rdr.records() {
+        if targets.len() >= count {
+            break;
+        }
+        let record = record?;
+        if record.get(0).is_none_or(|rank| rank.trim().parse::<u64>().is_err()) {
+            continue;
+        }
+        if let Some(domain) = record.get(1).map(str::trim).filter(|d| !d.is_empty()) {
+            let category = record.get(2).map(str::trim).filter(|c| !c.is_empty());
+            targets.push((domain.to_string(), category.map(str::to_string)));
+        }
+    }
+    Ok(targets)
+}
+
+fn merge_results(args: &Args) -> Result<()> {
+    let mut counter = Counter::default();
+    for input in &args.merge {
+        let results = Counter::from_results(input)?;
+        info!("Loaded {} results from {:?}", results.total(), input);
+        counter.merge(results);
+    }
+    info!("Merged {} domains \nSummary: {counter}", counter.total());
+    if args.summary_json {
+        println!("{}", serde_json::to_string(&counter.summary(0))?);
+    }
+    if let Some(output) = &args.output {
+        counter.save_results(output, args.format)?;
+    }
+    Ok(())
+}
+
+/// Origin ASN of `ip` from RIPEstat, so the agency can tell where the probe server is hosted.
+async fn lookup_asn(api_client: &Client, ip: IpAddr) -> Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct NetworkInfo {
+        data: NetworkInfoData,
+    }
+    #[derive(Deserialize)]
+    struct NetworkInfoData {
+        asns: Vec<String>,
+    }
+
+    let body = api_client.get(format!("https://stat.ripe.net/data/network-info/data.json?resource={ip}"))
+        .timeout(Duration::from_secs(10))
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+    let info: NetworkInfo = serde_json::from_slice(&body)?;
+    Ok(info.data.asns.first().map(|asn| format!("AS{asn}")))
+}
+
+/// Soft limit on open file descriptors, if the platform reports one.
+#[cfg(target_family = "unix")]
+fn open_file_limit() -> Option<usize> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    limit.rlim_cur.try_into().ok()
+}
+
+#[cfg(not(target_family = "unix"))]
+fn open_file_limit() -> Option<usize> {
+    None
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let mut args = Args::parse();
+    // RUST_LOG overrides the level, and can narrow logs down to one target, e.g. `[probe{target=example.com}]=debug`
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::builder().with_default_directive(LevelFilter::INFO.into()).from_env_lossy())
+        .init();
+
+    if !args.merge.is_empty() {
+        return merge_results(&args);
+    }
+
+    if let Some(file_limit) = open_file_limit() && args.probe_count + 128 > file_limit {
+        if args.no_clamp || file_limit <= 128 {
+            warn!("Open file limit is too low ({})! Consider increasing it using `ulimit -n`.", file_limit);
+        } else {
+            warn!("Open file limit is {}, reducing concurrent probes from {} to {}. Increase it using `ulimit -n` or pass --no-clamp.",
+                file_limit, args.probe_count, file_limit - 128);
+            args.probe_count = file_limit - 128;
+        }
+    }
+
+    if cfg!(not(feature = "http3")) && args.protocol == Protocol::H3 {
+        anyhow::bail!("--protocol h3 requires building with the http3 feature");
+    }
+    if let Some(proxy) = &args.proxy {
+        Proxy::all(proxy)?;
+    }
+    // The proxy would CONNECT to the SNI host rather than the target
+    if args.sni.is_some() && args.proxy.is_some() {
+        anyhow::bail!("--sni can't be combined with --proxy");
+    }
+    if args.quic_probe && (args.proxy.is_some() || args.http) {
+        anyhow::bail!("--quic-probe can't be combined with --proxy or --http");
+    }
+    if args.stream_output && args.format != OutputFormat::Csv {
+        anyhow::bail!("--stream-output only supports the csv format");
+    }
 
-    #[cfg(target_family = "unix")]
-    {
-        let file_limit: Option<usize> = unsafe { libc::getdtablesize() }.try_into().ok();
-        if matches!(file_limit, Some(file_limit) if file_limit <= args.probe_count + 128) {
-            warn!("Open file limit is too low ({})! Consider increasing it using `ulimit -n`.", file_limit.unwrap());
+    if args.tx {
+        let mut junk = vec![0u8; args.junk_size];
+        match args.seed {
+            Some(seed) => fastrand::Rng::with_seed(seed).fill(&mut junk),
+            None => getrandom::fill(&mut junk).map_err(|e| anyhow::anyhow!("Failed to generate junk: {e}"))?,
         }
+        JUNK.get_or_init(|| junk);
     }
 
     let api_client = Client::new();
     info!("Loading targets list...");
-    let targets = include_str!(concat!(env!("OUT_DIR"), "/list.csv"));
-    let targets: Vec<String> = targets.lines().take(args.count)
-        .map(|s| s.split(",").last().unwrap().to_string()).collect();
+    let mut categories = HashMap::new();
+    let mut targets: Vec<String> = match &args.targets {
+        Some(path) => load_targets(path, args.count)?.into_iter()
+            .map(|(target, category)| {
+                if let Some(category) = category {
+                    categories.insert(target.clone(), category);
+                }
+                target
+            })
+            .collect(),
+        None => {
+            let targets = include_str!(concat!(env!("OUT_DIR"), "/list.csv"));
+            targets.lines().take(args.count)
+                .map(|s| s.split(",").last().unwrap().to_string()).collect()
+        }
+    };
+
+    let baseline: Option<HashMap<String, Evidence>> = match &args.baseline {
+        Some(path) => Some(Counter::load_results(path)?.into_iter()
+            .map(|(target, result)| (target, result.evidence))
+            .collect()),
+        None => None,
+    };
+
+    let mut previous = HashMap::new();
+    if let Some(resume) = &args.resume {
+        previous.extend(Counter::load_results(resume)?);
+    }
+    if let (true, Some(output)) = (args.stream_output, &args.output) && output.exists() {
+        previous.extend(Counter::load_results(output)?);
+    }
+
+    let mut counter = Counter::default();
+    counter.categories = categories;
+    if !previous.is_empty() {
+        let resumed = counter.resume(previous);
+        targets.retain(|target| !counter.results.contains_key(target));
+        info!("Resumed {} results from previous run", resumed);
+    }
+
+    if args.dry_run {
+        info!("Reporter config: {:#?}", args.to_reporter_config());
+        info!("Would probe {} domains and upload to {}", targets.len(), args.agency_endpoint);
+        return Ok(());
+    }
+
+    if args.proxy.is_none() {
+        match lookup_asn(&api_client, args.ip[0]).await {
+            Ok(asn) => args.probe_asn = asn,
+            Err(e) => warn!("Failed to look up the ASN of {}: {}", args.ip[0], e),
+        }
+    }
+
+    if !args.skip_preflight && args.proxy.is_none() && !args.quic_probe && args.method == HttpMethod::Get {
+        preflight(&args).await?;
+    }
+
+    let mut stream = None;
+    if let (true, Some(output)) = (args.stream_output, &args.output) {
+        stream = Some(StreamWriter::open(output, args.flush_every)?);
+    }
 
     info!("Probing {} domains with {} concurrent probes...", targets.len(), args.probe_count);
     let sem = Arc::new(tokio::sync::Semaphore::new(args.probe_count));
+    let resolver = Arc::new(Resolver::new(&args.ip));
     let cancelled = wait_for_ctrlc();
     let start = Instant::now();
-    let mut futs = FuturesUnordered::new();
+    let started_at = Utc::now();
+    let deadline = args.max_duration.map(|max| start + max);
+    let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    let mut truncated = false;
+    if args.abort_on_error_rate.is_some() {
+        counter.track_error_rate(args.error_rate_window.max(1));
+    }
+    let failing = |counter: &Counter| match (args.abort_on_error_rate, counter.recent_error_rate()) {
+        (Some(max), Some(rate)) if rate > max => {
+            error!("{:.0}% of the last {} probes failed, the probe endpoint likely went down. Aborting",
+                   rate * 100.0, args.error_rate_window.max(1));
+            true
+        }
+        _ => false,
+    };
+    let mut aborted = false;
+    let mut unconfirmed = vec![];
+    let mut probes = JoinSet::new();
     for target in targets.into_iter().progress()
         .with_style(ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {human_pos}/{human_len} ({eta}, {per_sec})")?
@@ -151,67 +544,265 @@ async fn main() -> Result<()> {
         if cancelled() {
             break;
         }
+        if expired() {
+            warn!("Reached --max-duration, finishing up and saving...");
+            truncated = true;
+            break;
+        }
         let permit = sem.clone().acquire_owned().await?;
-        let args = args.clone();
-        let fake_target = args.fake.clone();
-        futs.push(tokio::spawn(async move {
-            let res = check_target(&args, fake_target.as_ref().unwrap_or(&target)).await;
-            drop(permit);
-            (target, res)
-        }));
+        spawn_probe(&mut probes, &args, &resolver, permit, target, 1);
+        // Probes finished so far are collected right away, keeping the error rate current
+        while let Some(res) = probes.try_join_next() {
+            collect(&args, &mut counter, &mut stream, &mut unconfirmed, res)?;
+        }
+        if failing(&counter) {
+            aborted = true;
+            break;
+        }
     }
-    info!("Collecting results...");
 
-    let mut counter = Counter::default();
-    while let Some(res) = futs.next().await {
-        match res {
-            Ok((target, Ok(Verdict::Accepted))) => {
-                counter.add(&target, Evidence::Ok);
-            }
-            Ok((target, Ok(Verdict::Blocked { early }))) => {
-                counter.add(&target, Evidence::Blocked);
-                if early {
-                    counter.early += 1;
-                }
+    if !aborted {
+        info!("Collecting results...");
+        while let Some(res) = probes.join_next().await {
+            collect(&args, &mut counter, &mut stream, &mut unconfirmed, res)?;
+            if failing(&counter) {
+                aborted = true;
+                break;
             }
-            Ok((target, Err(e))) if e.is_connect() => {
-                counter.add(&target, Evidence::ConnectError);
-                if args.verbosity >= Verbosity::Error {
-                    println!("{e:?}");
-                }
+        }
+    }
+    if aborted {
+        // Probes still in flight would keep hitting the failing endpoint
+        probes.abort_all();
+    }
+
+    if !unconfirmed.is_empty() {
+        if cancelled() || expired() || aborted {
+            for (target, result) in unconfirmed {
+                record(&mut counter, &mut stream, target, result, true, false)?;
             }
-            Ok((target, Err(_))) => {
-                counter.add(&target, Evidence::Error);
+        } else {
+            info!("Confirming {} early blocks...", unconfirmed.len());
+            let mut probes = JoinSet::new();
+            for (target, _) in unconfirmed {
+                let permit = sem.clone().acquire_owned().await?;
+                spawn_probe(&mut probes, &args, &resolver, permit, target, 2);
             }
-            Err(join_err) => {
-                error!("Task join error: {}", join_err);
+            while let Some(res) = probes.join_next().await {
+                if let Some((target, result, early)) = classify(&args, res) {
+                    record(&mut counter, &mut stream, target, result, early, true)?;
+                }
             }
-        };
+        }
     }
 
-    counter.print_results(&args.verbosity);
-    if let Some(output) = &args.output {
-        counter.save_results(output)?;
+    counter.print_results(&args.verbosity, args.group_by_site);
+    if let Some(stream) = &mut stream {
+        stream.flush()?;
+    } else if let Some(output) = &args.output {
+        counter.save_results(output, args.format)?;
     }
 
     info!("Probed {} domains in {}s! \nSummary: {counter}", counter.total(), start.elapsed().as_secs());
-    if let Err(e) = upload_results(&args, &api_client, counter.results).await {
+    if args.summary_json {
+        println!("{}", serde_json::to_string(&counter.summary(start.elapsed().as_secs()))?);
+    }
+    if let Some(baseline) = &baseline {
+        let changes = counter.diff(baseline);
+        info!("{} targets changed since the baseline:", changes.len());
+        for (target, before, after) in &changes {
+            println!("    [{} -> {}] {}", before, after, target);
+        }
+        if let Some(diff_output) = &args.diff_output {
+            Counter::save_diff(&changes, diff_output)?;
+        }
+    }
+    if aborted {
+        anyhow::bail!("aborted on --abort-on-error-rate, results were not uploaded");
+    }
+    let results = counter.results.into_iter()
+        .map(|(target, result)| (target, result.evidence))
+        .collect();
+    if let Err(e) = upload_results(&args, &api_client, started_at, truncated, results).await {
         warn!("Upload failed: {}", e);
     }
 
     Ok(())
 }
 
-async fn upload_results(args: &Args, api_client: &Client, results: HashMap<String, Evidence>) -> Result<()> {
-    info!("Uploading to {}", args.agency_endpoint);
+/// Harmless SNI the probe IPs are tried with before the run.
+const PREFLIGHT_SNI: &str = "example.com";
+
+/// Makes sure every probe IP serves a full response for `--path` to a harmless SNI.
+/// Otherwise every target would come out blocked and the whole run would be wasted.
+async fn preflight(args: &Args) -> Result<()> {
+    info!("Checking that the probe IPs serve /{}...", args.path);
+    let args = Args { sni: None, host: None, ..args.clone() };
+    for ip in &args.ip {
+        let resolver = Arc::new(Resolver::new(&[*ip]));
+        match check_target(&args, &resolver, PREFLIGHT_SNI, 1).await {
+            Ok((Verdict::Accepted, _)) => {}
+            Ok((Verdict::Blocked { cause: BlockCause::Truncated, .. }, timing)) => anyhow::bail!(
+                "{ip} returned {} bytes of /{} for {PREFLIGHT_SNI}, expected at least {}. \
+                 Check --ip and --path, or pass --skip-preflight",
+                timing.bytes, args.path, args.junk_size
+            ),
+            Ok((Verdict::Blocked { cause: BlockCause::Legal, .. }, _)) => anyhow::bail!(
+                "{ip} answered {PREFLIGHT_SNI} with a block page status (--block-status). \
+                 Check --ip and --block-status, or pass --skip-preflight"
+            ),
+            Ok((Verdict::Blocked { .. }, _)) => anyhow::bail!(
+                "{ip} timed out or reset the connection for {PREFLIGHT_SNI}. \
+                 Check --ip, or pass --skip-preflight"
+            ),
+            Err(e) => anyhow::bail!("{ip} can't be reached: {e}. Check --ip, or pass --skip-preflight"),
+        }
+    }
+    Ok(())
+}
+
+type Probe = (String, Result<(Verdict, Timing), ProbeError>);
+
+/// Why a probe reached no verdict.
+enum ProbeError {
+    Http(reqwest::Error),
+    Quic(anyhow::Error),
+}
+
+fn spawn_probe(probes: &mut JoinSet<Probe>, args: &Args, resolver: &Arc<Resolver>, permit: OwnedSemaphorePermit, target: String, timeout_scale: usize) {
+    let args = args.clone();
+    let resolver = resolver.clone();
+    // Every line logged while probing carries the target, so one domain can be filtered out
+    let span = info_span!("probe", target = %target);
+    probes.spawn(async move {
+        let probed = args.fake.as_ref().unwrap_or(&target);
+        let res = if args.quic_probe {
+            check_quic(&args, &resolver, probed, timeout_scale).await.map_err(ProbeError::Quic)
+        } else {
+            check_target(&args, &resolver, probed, timeout_scale).await.map_err(ProbeError::Http)
+        };
+        drop(permit);
+        (target, res)
+    }.instrument(span));
+}
+
+/// Records a finished probe into the counter and stream, holding early blocks back
+/// when `--confirm-blocks` re-probes them.
+fn collect(args: &Args, counter: &mut Counter, stream: &mut Option<StreamWriter>,
+           unconfirmed: &mut Vec<(String, ProbeResult)>, res: Result<Probe, JoinError>) -> Result<()> {
+    let Some((target, result, early)) = classify(args, res) else { return Ok(()) };
+    if early && args.confirm_blocks {
+        unconfirmed.push((target, result));
+        Ok(())
+    } else {
+        record(counter, stream, target, result, early, false)
+    }
+}
+
+fn record(counter: &mut Counter, stream: &mut Option<StreamWriter>,
+          target: String, result: ProbeResult, early: bool, confirmed: bool) -> Result<()> {
+    if early {
+        counter.add_early(confirmed);
+    }
+    if let Some(stream) = stream {
+        stream.write(&target, &result)?;
+    }
+    counter.add(&target, result);
+    Ok(())
+}
+
+/// Turns a finished probe into a result, also telling whether it was blocked at the connect stage.
+fn classify(args: &Args, res: Result<Probe, JoinError>) -> Option<(String, ProbeResult, bool)> {
+    let (target, evidence, timing, early) = match res {
+        Ok((target, Ok((Verdict::Accepted, timing)))) => (target, Evidence::Ok, timing, false),
+        Ok((target, Ok((Verdict::Blocked { early, cause }, timing)))) => {
+            let evidence = match cause {
+                BlockCause::Truncated => Evidence::Blocked,
+                BlockCause::Timeout => Evidence::Timeout,
+                BlockCause::Reset => Evidence::Reset,
+                BlockCause::Quic => Evidence::QuicBlocked,
+                BlockCause::Legal => Evidence::LegalBlock,
+            };
+            (target, evidence, timing, early)
+        }
+        Ok((target, Err(ProbeError::Http(e)))) if e.is_connect() => {
+            if args.verbosity >= Verbosity::Error {
+                println!("{e:?}");
+            }
+            (target, Evidence::ConnectError, Timing::default(), false)
+        }
+        Ok((target, Err(ProbeError::Quic(e)))) => {
+            if args.verbosity >= Verbosity::Error {
+                println!("{e:?}");
+            }
+            (target, Evidence::Error, Timing::default(), false)
+        }
+        Ok((target, Err(_))) => (target, Evidence::Error, Timing::default(), false),
+        Err(join_err) => {
+            error!("Task join error: {}", join_err);
+            return None;
+        }
+    };
+    let result = ProbeResult {
+        evidence,
+        duration_ms: timing.elapsed.as_millis() as u64,
+        bytes: timing.bytes,
+    };
+    Some((target, result, early))
+}
+
+/// Uploads results in chunks of `--upload-batch`, all tagged with the same run id
+/// so the agency can put them back together. A failed chunk doesn't stop the rest.
+async fn upload_results(args: &Args, api_client: &Client, started_at: DateTime<Utc>, truncated: bool, results: HashMap<String, Evidence>) -> Result<()> {
+    let run_id = Uuid::new_v4();
+    let batch = args.upload_batch.max(1);
+    let chunks = results.len().div_ceil(batch).max(1);
+    info!("Uploading to {} in {} chunk(s), run {}", args.agency_endpoint, chunks, run_id);
+
+    let mut results = results.into_iter();
+    let mut failed = 0;
+    for chunk in 1..=chunks {
+        let data: HashMap<String, Evidence> = results.by_ref().take(batch).collect();
+        match upload_chunk(args, api_client, run_id, started_at, truncated, data).await {
+            Ok(()) => info!("Chunk {}/{} uploaded", chunk, chunks),
+            Err(e) => {
+                warn!("Chunk {}/{} failed: {}", chunk, chunks, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} chunks failed", failed, chunks);
+    }
+    Ok(())
+}
 
+/// Evidence labels repeat a lot, so a fast level already gets most of the gain.
+const ZSTD_LEVEL: i32 = 3;
+
+async fn upload_chunk(args: &Args, api_client: &Client, run_id: Uuid, started_at: DateTime<Utc>, truncated: bool, data: HashMap<String, Evidence>) -> Result<()> {
+    let body = rmp_serde::to_vec(&AgencyReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        config: args.to_reporter_config(),
+        data,
+        run_id: Some(run_id),
+        started_at: Some(started_at),
+        schema: SCHEMA,
+        truncated,
+    })?;
     let uploaded = api_client.post(&args.agency_endpoint)
         .header("Content-Type", "application/msgpack")
-        .body(rmp_serde::to_vec(&AgencyReport {
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            config: args.to_reporter_config(),
-            data: results,
-        })?);
+        .header("X-Run-Id", run_id.to_string());
+
+    let uploaded = if args.compress {
+        let compressed = zstd::encode_all(body.as_slice(), ZSTD_LEVEL)?;
+        info!("Compressed report from {} to {} bytes ({:.1}x)",
+              body.len(), compressed.len(), body.len() as f64 / compressed.len().max(1) as f64);
+        uploaded.header("Content-Encoding", "zstd").body(compressed)
+    } else {
+        uploaded.body(body)
+    };
 
     let uploaded = if let Some(key) = &args.key {
         uploaded.header("Authorization", format!("Bearer {key}"))
@@ -219,12 +810,11 @@ async fn upload_results(args: &Args, api_client: &Client, results: HashMap<Strin
 
     let uploaded = uploaded.send().await?;
 
-    if uploaded.status().is_success() {
-        info!("Uploaded ({})!", uploaded.status().to_string());
-    } else {
-        warn!("Upload failed: {}", uploaded.status().to_string());
-    }
+    let status = uploaded.status();
     info!("Agency response: {}", uploaded.text().await?);
+    if !status.is_success() {
+        anyhow::bail!("agency returned {}", status);
+    }
     Ok(())
 }
 
@@ -251,62 +841,150 @@ fn wait_for_ctrlc() -> impl Fn() -> bool {
     }
 }
 
+/// Time spent on the final attempt and the number of body bytes it received.
+#[derive(Default)]
+struct Timing {
+    elapsed: Duration,
+    bytes: usize,
+}
+
 enum Verdict {
-    Blocked { early: bool },
+    Blocked { early: bool, cause: BlockCause },
     Accepted,
 }
 
-async fn check_target(args: &Args, target: &str) -> Result<Verdict, reqwest::Error> {
-    let url = format!("http{}://{target}/{}", if args.http {""} else {"s"}, args.path);
+enum BlockCause {
+    /// Response ended early or with a non-OK status
+    Truncated,
+    Timeout,
+    /// Connection was torn down, typically by an injected TCP RST
+    Reset,
+    /// QUIC handshake got no answer or was reset
+    Quic,
+    /// Status listed in `--block-status`, e.g. 451
+    Legal,
+}
+
+/// Looks through the error sources for a connection reset or abort.
+fn is_reset(e: &reqwest::Error) -> bool {
+    let mut source = e.source();
+    while let Some(err) = source {
+        if err.downcast_ref::<std::io::Error>()
+            .is_some_and(|io| matches!(io.kind(), std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted)) {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Sleeps `retry_backoff_ms * 2^(attempt-1)` plus up to half of that as random jitter.
+async fn retry_backoff(args: &Args, attempt: usize) {
+    if args.retry_backoff_ms == 0 {
+        return;
+    }
+    let delay = args.retry_backoff_ms.saturating_mul(1 << (attempt - 1).min(16));
+    let jitter = getrandom::u64().unwrap_or(0) % (delay / 2 + 1);
+    tokio::time::sleep(Duration::from_millis(delay + jitter)).await;
+}
+
+/// QUIC handshake counterpart of [`check_target`], retried while it looks blocked.
+async fn check_quic(args: &Args, resolver: &Resolver, target: &str, timeout_scale: usize) -> Result<(Verdict, Timing)> {
+    let sni = args.sni.as_deref().unwrap_or(target);
+    let timeout = Duration::from_secs(args.timeout_secs * timeout_scale as u64);
     let mut attempts = 0;
 
     loop {
         attempts += 1;
-        let client = build_client(&args, 1)?;
-        let mut resp = client.get(&url)
-            .header("Range", "bytes=0-65536");
-        if args.tx {
-            resp = resp.body(JUNK)
-        }
-        let resp = resp.send()
-            .await;
-
-        let resp = match resp {
-            Ok(resp) => match (resp.status(), resp.bytes().await) {
-                (status, Ok(b)) => Ok((status, b)),
-                (_, Err(e)) => Err((e, false)),
-            },
-            Err(e) => Err((e, true)),
-        };
+        let ip = resolver.next_ip();
+        let span = info_span!("attempt", n = attempts, %ip);
+        let result = quic::probe(ip, sni, timeout).instrument(span.clone()).await;
+        if attempts < args.retry_count && !matches!(result, Ok((Verdict::Accepted, _))) {
+            retry_backoff(args, attempts).await;
+            continue;
+        }
+        if let Err(e) = &result {
+            span.in_scope(|| error!("{} -> QUIC error: {:?}", target, e));
+        }
+        return result;
+    }
+}
+
+/// `timeout_scale` multiplies the read timeout, used to give suspected blocks a second chance.
+async fn check_target(args: &Args, resolver: &Arc<Resolver>, target: &str, timeout_scale: usize) -> Result<(Verdict, Timing), reqwest::Error> {
+    // The resolver pins every name to the probe IPs, so the URL host only decides the SNI
+    let sni = args.sni.as_deref().unwrap_or(target);
+    let url = format!("http{}://{sni}/{}", if args.http {""} else {"s"}, args.path);
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        // The resolver fills in the IP once the connection is made
+        let span = info_span!("attempt", n = attempts, ip = field::Empty);
+        let started = Instant::now();
+        let client = build_client(args, resolver, timeout_scale)?;
+        let mut resp = client.request(args.method.into(), &url)
+            .header("Range", format!("bytes=0-{}", args.junk_size));
+        if args.protocol == Protocol::H3 {
+            resp = resp.version(reqwest::Version::HTTP_3);
+        }
+        if args.sni.is_some() || args.host.is_some() {
+            resp = resp.header(reqwest::header::HOST, args.host.as_deref().unwrap_or(target));
+        }
+        for (name, value) in &args.headers {
+            resp = resp.header(name, value);
+        }
+        if let Some(junk) = JUNK.get() {
+            resp = resp.body(junk.as_slice())
+        }
+        let resp = async {
+            match resp.send().await {
+                Ok(resp) => match (resp.status(), resp.bytes().await) {
+                    (status, Ok(b)) => Ok((status, b)),
+                    (_, Err(e)) => Err((e, false)),
+                },
+                Err(e) => Err((e, true)),
+            }
+        }.instrument(span.clone()).await;
         return match resp {
             Ok((status, bytes)) => {
+                let timing = Timing { elapsed: started.elapsed(), bytes: bytes.len() };
+                // An explicit block page, retrying won't change it
+                if args.block_status.contains(&status.as_u16()) {
+                    return Ok((Verdict::Blocked { early: false, cause: BlockCause::Legal }, timing));
+                }
                 let warn = if !status.is_success() {
                     Some(format!("Domain {target} returned non-OK code: {status}"))
-                } else if bytes.len() < 65535 {
+                } else if args.method == HttpMethod::Get && bytes.len() < args.junk_size.saturating_sub(1) {
                     Some(format!("Domain {target} completed with {} bytes: \n{}", bytes.len(), String::from_utf8_lossy(bytes.as_ref())))
                 } else {
                     None
                 };
 
                 if let Some(warn) = warn {
-                    warn!("{warn}");
+                    span.in_scope(|| warn!("{warn}"));
                     if attempts < args.retry_count {
+                        retry_backoff(args, attempts).await;
                         continue;
                     } else {
-                        return Ok(Verdict::Blocked { early: false });
+                        return Ok((Verdict::Blocked { early: false, cause: BlockCause::Truncated }, timing));
                     }
                 }
 
-                Ok(Verdict::Accepted)
+                Ok((Verdict::Accepted, timing))
             }
             Err((e, early)) => {
                 if attempts < args.retry_count {
+                    retry_backoff(args, attempts).await;
                     continue;
                 }
+                let timing = Timing { elapsed: started.elapsed(), bytes: 0 };
                 if e.is_timeout() {
-                    Ok(Verdict::Blocked { early })
+                    Ok((Verdict::Blocked { early, cause: BlockCause::Timeout }, timing))
+                } else if is_reset(&e) {
+                    Ok((Verdict::Blocked { early, cause: BlockCause::Reset }, timing))
                 } else {
-                    error!("{} -> Error: {:?}", target, e);
+                    span.in_scope(|| error!("{} -> Error: {:?}", target, e));
                     Err(e)
                 }
             },
@@ -0,0 +1,74 @@
+use crate::{BlockCause, Timing, Verdict};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, ConnectionError, Endpoint};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Accepts any certificate: the probe only cares whether the handshake gets through,
+/// like the HTTP probes with `danger_accept_invalid_certs`.
+#[derive(Debug)]
+struct AnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCert {
+    fn verify_server_cert(&self, _: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn client_config() -> anyhow::Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCert(provider)))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    Ok(ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?)))
+}
+
+/// Attempts a bare QUIC handshake with `ip:443` using `sni`, without sending any request.
+/// No answer within `timeout` or a stateless reset counts as a QUIC block;
+/// the server closing the connection still proves QUIC gets through.
+pub async fn probe(ip: IpAddr, sni: &str, timeout: Duration) -> anyhow::Result<(Verdict, Timing)> {
+    let bind = match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let mut endpoint = Endpoint::client(SocketAddr::new(bind, 0))?;
+    endpoint.set_default_client_config(client_config()?);
+
+    let started = Instant::now();
+    let connecting = endpoint.connect(SocketAddr::new(ip, 443), sni)?;
+    let result = tokio::time::timeout(timeout, connecting).await;
+    let timing = Timing { elapsed: started.elapsed(), bytes: 0 };
+    let blocked = Verdict::Blocked { early: true, cause: BlockCause::Quic };
+    let verdict = match result {
+        Ok(Ok(connection)) => {
+            connection.close(0u32.into(), b"");
+            Verdict::Accepted
+        }
+        Ok(Err(ConnectionError::ConnectionClosed(_) | ConnectionError::ApplicationClosed(_) | ConnectionError::VersionMismatch)) => Verdict::Accepted,
+        Ok(Err(ConnectionError::TimedOut | ConnectionError::Reset)) | Err(_) => blocked,
+        Ok(Err(e)) => return Err(e.into()),
+    };
+    endpoint.close(0u32.into(), b"");
+    Ok((verdict, timing))
+}
@@ -1,21 +1,31 @@
 use reqwest::dns::{Addrs, Name, Resolve, Resolving};
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+/// Pins every name to one of the probe IPs, rotating through them per request.
 pub struct Resolver {
-    ip: SocketAddr,
+    ips: Vec<SocketAddr>,
+    next: AtomicUsize,
 }
 
 impl Resolver {
-    pub fn new(ip: IpAddr) -> Resolver {
+    pub fn new(ips: &[IpAddr]) -> Resolver {
         Resolver {
-            ip: SocketAddr::from(SocketAddr::new(ip, 0)),
+            ips: ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect(),
+            next: AtomicUsize::new(0),
         }
     }
+
+    /// Next probe IP in the rotation.
+    pub fn next_ip(&self) -> IpAddr {
+        self.ips[self.next.fetch_add(1, Ordering::Relaxed) % self.ips.len()].ip()
+    }
 }
 
 impl Resolve for Resolver {
     fn resolve(&self, _: Name) -> Resolving {
-        let ip = self.ip.clone();
+        let ip = SocketAddr::new(self.next_ip(), 0);
+        tracing::Span::current().record("ip", tracing::field::display(ip.ip()));
         Box::pin(async move {
             Ok(Addrs::from(Box::new(vec![ip].into_iter())))
         })
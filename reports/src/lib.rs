@@ -1,21 +1,111 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::de::{IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
-use std::fmt::Display;
+use std::fmt::{Display, Formatter};
 use std::net::IpAddr;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Layout version of [`AgencyReport`]. Bump it whenever fields or [`Evidence`] kinds are added.
+pub const SCHEMA: u8 = 8;
+
+/// Position of `schema` in the array encoding. New fields go after it.
+const SCHEMA_INDEX: usize = 5;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgencyReport {
     pub version: String,
     pub config: ReporterConfig,
     pub data: HashMap<String, Evidence>,
+    /// Shared by every chunk uploaded from the same run; absent from older reporters
+    #[serde(default)]
+    pub run_id: Option<Uuid>,
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    /// Reports from before versioning are schema 1
+    #[serde(default = "default_schema")]
+    pub schema: u8,
+    /// Probing stopped at the reporter's `--max-duration` before every target was probed
+    #[serde(default)]
+    pub truncated: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+fn default_schema() -> u8 {
+    1
+}
+
+/// Schema of an encoded [`AgencyReport`], read without decoding the rest,
+/// so that reports too new to be decoded can be told apart from broken ones.
+pub struct SchemaPeek(pub u8);
+
+impl<'de> Deserialize<'de> for SchemaPeek {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PeekVisitor;
+
+        impl<'de> Visitor<'de> for PeekVisitor {
+            type Value = SchemaPeek;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                f.write_str("an agency report")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<SchemaPeek, A::Error> {
+                let mut schema = default_schema();
+                let mut index = 0;
+                while index < SCHEMA_INDEX && seq.next_element::<IgnoredAny>()?.is_some() {
+                    index += 1;
+                }
+                if index == SCHEMA_INDEX {
+                    schema = seq.next_element()?.unwrap_or(schema);
+                }
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+                Ok(SchemaPeek(schema))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SchemaPeek, A::Error> {
+                let mut schema = default_schema();
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == "schema" {
+                        schema = map.next_value()?;
+                    } else {
+                        map.next_value::<IgnoredAny>()?;
+                    }
+                }
+                Ok(SchemaPeek(schema))
+            }
+        }
+
+        deserializer.deserialize_any(PeekVisitor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Evidence {
     Ok,
     Blocked,
     ConnectError,
     Error,
+    /// No response in time, typically silently dropped packets
+    Timeout,
+    /// Connection was reset, typically by an injected TCP RST
+    Reset,
+    /// QUIC handshake was dropped while TCP may still get through
+    QuicBlocked,
+    /// Server answered with a block page status such as 451 Unavailable For Legal Reasons
+    LegalBlock,
+}
+
+impl Evidence {
+    /// Whether the probe reached a verdict; errors are worth retrying on resume.
+    pub fn is_conclusive(&self) -> bool {
+        self.is_block() || matches!(self, Evidence::Ok)
+    }
+
+    /// Any of the blocking mechanisms.
+    pub fn is_block(&self) -> bool {
+        matches!(self, Evidence::Blocked | Evidence::Timeout | Evidence::Reset | Evidence::QuicBlocked | Evidence::LegalBlock)
+    }
 }
 
 impl Display for Evidence {
@@ -25,11 +115,33 @@ impl Display for Evidence {
             Evidence::Blocked => "blocked",
             Evidence::ConnectError => "connect_error",
             Evidence::Error => "unknown_error",
+            Evidence::Timeout => "timeout",
+            Evidence::Reset => "reset",
+            Evidence::QuicBlocked => "quic_blocked",
+            Evidence::LegalBlock => "legal_block",
         };
         write!(f, "{}", str)
     }
 }
 
+impl FromStr for Evidence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ok" => Ok(Evidence::Ok),
+            "blocked" => Ok(Evidence::Blocked),
+            "connect_error" => Ok(Evidence::ConnectError),
+            "unknown_error" => Ok(Evidence::Error),
+            "timeout" => Ok(Evidence::Timeout),
+            "reset" => Ok(Evidence::Reset),
+            "quic_blocked" => Ok(Evidence::QuicBlocked),
+            "legal_block" => Ok(Evidence::LegalBlock),
+            _ => Err(format!("unknown evidence: {s}")),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReporterConfig {
     pub http: bool,
@@ -39,4 +151,62 @@ pub struct ReporterConfig {
     pub retry_count: usize,
     pub timeout_secs: u64,
     pub probe_count: usize,
+    /// Number of probe IPs requests were spread across
+    #[serde(default = "default_probe_ip_count")]
+    pub probe_ip_count: usize,
+    /// HTTP version probes were forced to
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Probes went through a proxy instead of the probe IPs
+    #[serde(default)]
+    pub proxied: bool,
+    /// Redirects followed per request, 0 when they count as non-OK responses
+    #[serde(default)]
+    pub follow_redirects: usize,
+    /// Seed the junk payload was generated from, absent for random junk
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// ASN of the first probe IP, e.g. `AS24940`, when it could be looked up
+    #[serde(default)]
+    pub probe_asn: Option<String>,
+}
+
+fn default_probe_ip_count() -> usize {
+    1
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    /// Whatever the client negotiates
+    #[default]
+    Auto,
+    H1,
+    H2,
+    H3,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            Protocol::Auto => "auto",
+            Protocol::H1 => "h1",
+            Protocol::H2 => "h2",
+            Protocol::H3 => "h3",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Protocol::Auto),
+            "h1" => Ok(Protocol::H1),
+            "h2" => Ok(Protocol::H2),
+            "h3" => Ok(Protocol::H3),
+            _ => Err(format!("unknown protocol: {s}")),
+        }
+    }
 }
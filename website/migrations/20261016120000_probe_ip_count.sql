@@ -0,0 +1,2 @@
+ALTER TABLE reports
+    ADD COLUMN IF NOT EXISTS probe_ip_count INT NOT NULL DEFAULT 1;
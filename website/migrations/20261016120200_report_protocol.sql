@@ -0,0 +1,2 @@
+ALTER TABLE reports
+    ADD COLUMN IF NOT EXISTS protocol VARCHAR(8) NOT NULL DEFAULT 'auto';
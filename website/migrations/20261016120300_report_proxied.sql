@@ -0,0 +1,2 @@
+ALTER TABLE reports
+    ADD COLUMN IF NOT EXISTS proxied BOOLEAN NOT NULL DEFAULT FALSE;
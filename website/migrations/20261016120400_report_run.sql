@@ -0,0 +1,5 @@
+ALTER TABLE reports
+    ADD COLUMN IF NOT EXISTS run_id     UUID,
+    ADD COLUMN IF NOT EXISTS started_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS reports_run_id_idx ON reports (run_id);
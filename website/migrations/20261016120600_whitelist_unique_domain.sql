@@ -0,0 +1,2 @@
+-- REFRESH MATERIALIZED VIEW CONCURRENTLY needs a unique index
+CREATE UNIQUE INDEX IF NOT EXISTS whitelist_domain_idx ON whitelist (domain);
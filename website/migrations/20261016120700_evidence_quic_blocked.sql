@@ -0,0 +1 @@
+ALTER TYPE evidence ADD VALUE IF NOT EXISTS 'quic_blocked';
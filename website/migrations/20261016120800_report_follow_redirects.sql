@@ -0,0 +1,2 @@
+ALTER TABLE reports
+    ADD COLUMN IF NOT EXISTS follow_redirects INT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE queries
+    ADD COLUMN IF NOT EXISTS dns_divergence BOOLEAN;
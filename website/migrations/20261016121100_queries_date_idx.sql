@@ -0,0 +1,2 @@
+-- Time-windowed aggregates over queries, e.g. /stats/top-blocked
+CREATE INDEX IF NOT EXISTS queries_date_idx ON queries (date);
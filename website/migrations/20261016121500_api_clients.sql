@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS api_clients
+(
+    id         SERIAL PRIMARY KEY,
+    name       VARCHAR(255) NOT NULL,
+    token      VARCHAR(255) NOT NULL UNIQUE,
+    -- requests per minute, 0 for unlimited
+    per_minute INTEGER      NOT NULL DEFAULT 600
+);
@@ -0,0 +1,53 @@
+-- Domains the whitelist accepts: at least half of their last 5 checks by reporter 1
+-- came back ok. Shared by the view and the delta reported for each upload, so the
+-- two can't drift apart. `only_domains` limits it to those domains, and
+-- `excluded_report` evaluates it as if that report hadn't been stored.
+CREATE OR REPLACE FUNCTION whitelisted_domains(only_domains TEXT[] DEFAULT NULL, excluded_report INTEGER DEFAULT NULL)
+    RETURNS TABLE
+            (
+                domain  VARCHAR(255),
+                last_ok TIMESTAMP
+            )
+    LANGUAGE sql
+    STABLE
+AS
+$$
+WITH ranked_reports AS (SELECT rr.domain,
+                               rr.evidence,
+                               r.date,
+                               ROW_NUMBER() OVER (
+                                   PARTITION BY
+                                       rr.domain
+                                   ORDER BY
+                                       r.date DESC
+                                   ) AS rn
+                        FROM report_row rr
+                                 JOIN reports r ON rr.report_id = r.id
+                        WHERE r.reporter = 1
+                          AND (only_domains IS NULL OR rr.domain = ANY (only_domains))
+                          AND (excluded_report IS NULL OR r.id <> excluded_report))
+SELECT rr.domain,
+       MAX(
+               CASE
+                   WHEN rr.evidence = 'ok' THEN rr.date
+                   END
+       ) AS last_ok
+FROM ranked_reports rr
+WHERE rr.rn <= 5
+GROUP BY rr.domain
+HAVING COUNT(*) FILTER (
+    WHERE
+    rr.evidence = 'ok'
+    ) >= COUNT(*) / 2.0
+$$;
+
+DROP MATERIALIZED VIEW IF EXISTS whitelist;
+CREATE MATERIALIZED VIEW whitelist AS
+SELECT w.domain,
+       d.rank,
+       w.last_ok
+FROM whitelisted_domains() w
+         LEFT JOIN domains d ON d.domain = w.domain
+ORDER BY d.rank;
+-- REFRESH MATERIALIZED VIEW CONCURRENTLY needs a unique index
+CREATE UNIQUE INDEX IF NOT EXISTS whitelist_domain_idx ON whitelist (domain);
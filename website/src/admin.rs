@@ -0,0 +1,118 @@
+use crate::Db;
+use rocket::http::Status;
+use rocket::outcome::{try_outcome, IntoOutcome};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::Request;
+use rocket_db_pools::Connection;
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDateTime;
+use sqlx::types::Uuid;
+
+const PER_PAGE: i64 = 50;
+
+/// Bearer token matching `ADMIN_TOKEN`. Without it set every admin route is unauthorized.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = try_outcome!(
+            request.headers().get_one("Authorization")
+                .and_then(|t| t.split_once(" "))
+                .map(|(_, tok)| tok.to_string())
+                .or_forward(Status::Unauthorized)
+        );
+        std::env::var("ADMIN_TOKEN").ok()
+            .filter(|admin| !admin.is_empty() && *admin == token)
+            .map(|_| Admin)
+            .or_forward(Status::Unauthorized)
+    }
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct QueryRow {
+    #[serde(serialize_with = "serialize_uuid")]
+    id: Uuid,
+    query: String,
+    source_country_code: Option<String>,
+    target_country_code: Option<String>,
+    target_asn: Option<String>,
+    target_provider: Option<String>,
+    resolved_ips: Option<Vec<String>>,
+    cdn_providers: Option<Vec<String>>,
+    rkn_domain: Option<String>,
+    dns_divergence: Option<bool>,
+    blocked: bool,
+    date: Option<NaiveDateTime>,
+}
+
+fn serialize_uuid<S: serde::Serializer>(id: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueriesPage {
+    total: i64,
+    page: i64,
+    per_page: i64,
+    pages: i64,
+    rows: Vec<QueryRow>,
+}
+
+/// Saved checks, newest first. `page` starts at 1, `country` is the target's
+/// country code and `blocked` matches RKN domain or CDN hits.
+#[get("/queries?<page>&<country>&<blocked>")]
+pub async fn queries(
+    _admin: Admin,
+    mut db: Connection<Db>,
+    page: Option<i64>,
+    country: Option<&str>,
+    blocked: Option<bool>,
+) -> Result<Json<QueriesPage>, Status> {
+    let page = page.unwrap_or(1).max(1);
+    let filter = "WHERE ($1::VARCHAR IS NULL OR target_country_code = $1)
+          AND ($2::BOOLEAN IS NULL OR (rkn_domain IS NOT NULL OR COALESCE(cardinality(cdn_providers), 0) > 0) = $2)";
+
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM queries {filter}"))
+        .bind(country)
+        .bind(blocked)
+        .fetch_one(&mut **db)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    let rows: Vec<QueryRow> = sqlx::query_as(&format!(
+        "SELECT id,
+                query,
+                source_country_code,
+                target_country_code,
+                target_asn,
+                target_provider,
+                resolved_ips,
+                cdn_providers,
+                rkn_domain,
+                dns_divergence,
+                (rkn_domain IS NOT NULL OR COALESCE(cardinality(cdn_providers), 0) > 0) AS blocked,
+                date
+        FROM queries {filter}
+        ORDER BY date DESC
+        LIMIT $3 OFFSET $4"
+    ))
+        .bind(country)
+        .bind(blocked)
+        .bind(PER_PAGE)
+        .bind((page - 1).saturating_mul(PER_PAGE))
+        .fetch_all(&mut **db)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(QueriesPage {
+        total,
+        page,
+        per_page: PER_PAGE,
+        pages: (total + PER_PAGE - 1) / PER_PAGE,
+        rows,
+    }))
+}
@@ -1,30 +1,129 @@
 use crate::Db;
-use reports::AgencyReport;
+use log::error;
+use reports::{AgencyReport, Evidence, SchemaPeek, SCHEMA};
+use rocket::data::{Data, Limits};
+use rocket::fairing::AdHoc;
 use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
 use rocket::serde::json::serde_json::json;
 use rocket::serde::json::{Json, Value};
-use rocket::serde::msgpack::MsgPack;
+use rocket::serde::msgpack;
+use rocket::tokio::sync::Notify;
+use rocket::tokio::{self, time};
+use rocket::{Request, State};
 use rocket_client_addr::ClientRealAddr;
-use rocket_db_pools::Connection;
-use sqlx::Acquire;
+use rocket_db_pools::{Connection, Database};
+use sqlx::types::Uuid;
+use sqlx::{Acquire, PgConnection, PgPool};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_TIMEOUT_SECS: u64 = 120;
+const MAX_RETRY_COUNT: usize = 10;
+const MAX_REPORT_ROWS: usize = 1_000_000;
 
 pub struct Agency {
     pub id: i32,
     pub name: String,
 }
 
+/// Refreshes the `whitelist` view in the background. Requests arriving within
+/// `WHITELIST_REFRESH_DEBOUNCE_SECS` of each other share a single refresh.
+pub struct WhitelistRefresh(Arc<Notify>);
+
+impl WhitelistRefresh {
+    pub fn request(&self) {
+        self.0.notify_one();
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::try_on_ignite("Whitelist refresh", |rocket| async move {
+            let Some(db) = Db::fetch(&rocket) else {
+                return Err(rocket);
+            };
+            let pool: PgPool = (**db).clone();
+            let window = Duration::from_secs(
+                std::env::var("WHITELIST_REFRESH_DEBOUNCE_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(10),
+            );
+            let notify = Arc::new(Notify::new());
+
+            let requests = notify.clone();
+            tokio::spawn(async move {
+                loop {
+                    requests.notified().await;
+                    time::sleep(window).await;
+                    if let Err(e) = sqlx::query("REFRESH MATERIALIZED VIEW CONCURRENTLY whitelist")
+                        .execute(&pool)
+                        .await
+                    {
+                        error!("Failed to refresh whitelist: {}", e);
+                    }
+                }
+            });
+
+            Ok(rocket.manage(WhitelistRefresh(notify)))
+        })
+    }
+}
+
+/// `Content-Encoding` of the request body, `None` when sent as is.
+pub struct ContentEncoding(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ContentEncoding {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ContentEncoding(request.headers().get_one("Content-Encoding").map(str::to_string)))
+    }
+}
+
+/// Reports may be zstd compressed (`Content-Encoding: zstd`), and are then held
+/// to the `msgpack` limit once decompressed.
 #[rocket::post("/report", format = "application/msgpack", data = "<report>")]
 pub async fn upload_report(
-    report: MsgPack<AgencyReport>,
+    report: Data<'_>,
+    encoding: ContentEncoding,
+    limits: &Limits,
     addr: &ClientRealAddr,
     agency: Agency,
+    whitelist: &State<WhitelistRefresh>,
     mut db: Connection<Db>,
 ) -> Result<Json<Value>, (Status, String)> {
+    let limit = limits.get("msgpack").unwrap_or(Limits::MESSAGE_PACK);
+    let bytes = report
+        .open(limit)
+        .into_bytes()
+        .await
+        .map_err(|e| (Status::BadRequest, e.to_string()))?;
+    if !bytes.is_complete() {
+        return Err((Status::PayloadTooLarge, "report is too large".to_string()));
+    }
+    let bytes = match encoding.0.as_deref() {
+        None | Some("identity") => bytes.into_inner(),
+        Some("zstd") => decompress_zstd(&bytes, limit.as_u64())?,
+        Some(other) => return Err((Status::UnsupportedMediaType, format!("unsupported content encoding {other}"))),
+    };
+    let report = decode_report(&bytes)?;
+    validate_report(&report).map_err(|e| (Status::UnprocessableEntity, e))?;
+
     let mut tx = db
         .begin()
         .await
         .map_err(|e| (Status::InternalServerError, e.to_string()))?;
-    let report = report.into_inner();
+
+    if let Some(run_id) = report.run_id
+        && let Some(domain) = report.data.keys().next()
+        && let Some(report_id) = uploaded_chunk(&mut tx, run_id, domain)
+            .await
+            .map_err(|e| (Status::InternalServerError, e.to_string()))?
+    {
+        return Ok(Json(json!({ "ok": true, "id": report_id, "duplicate": true })));
+    }
 
     let report_id: i32 = sqlx::query_scalar(
         "INSERT INTO reports (
@@ -37,8 +136,17 @@ pub async fn upload_report(
                     path,
                     retry_count,
                     timeout_secs,
-                    probe_count
-                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id",
+                    probe_count,
+                    probe_ip_count,
+                    protocol,
+                    proxied,
+                    follow_redirects,
+                    seed,
+                    probe_asn,
+                    run_id,
+                    started_at,
+                    truncated
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19) RETURNING id",
     )
     .bind(agency.id)
     .bind(addr.ip.to_string())
@@ -50,6 +158,15 @@ pub async fn upload_report(
     .bind(report.config.retry_count as i32)
     .bind(report.config.timeout_secs as i64)
     .bind(report.config.probe_count as i32)
+    .bind(report.config.probe_ip_count as i32)
+    .bind(report.config.protocol.to_string())
+    .bind(report.config.proxied)
+    .bind(report.config.follow_redirects as i32)
+    .bind(report.config.seed.map(|seed| seed as i64))
+    .bind(report.config.probe_asn)
+    .bind(report.run_id)
+    .bind(report.started_at)
+    .bind(report.truncated)
     .fetch_one(&mut *tx)
     .await
     .map_err(|e| (Status::InternalServerError, e.to_string()))?;
@@ -60,7 +177,7 @@ pub async fn upload_report(
         .map_err(|e| (Status::InternalServerError, e.to_string()))?;
 
     for (domain, evidence) in report.data {
-        let line = format!("{},{},{}\n", report_id, evidence, domain);
+        let line = format!("{},{},{}\n", report_id, evidence_label(&evidence), domain);
         copy_in
             .send(line.as_bytes())
             .await
@@ -72,14 +189,122 @@ pub async fn upload_report(
         .await
         .map_err(|e| (Status::InternalServerError, e.to_string()))?;
 
-    sqlx::query!("REFRESH MATERIALIZED VIEW whitelist")
-        .execute(&mut *tx)
+    let (added, removed) = whitelist_delta(&mut tx, report_id)
         .await
         .map_err(|e| (Status::InternalServerError, e.to_string()))?;
 
     tx.commit()
         .await
         .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    whitelist.request();
+
+    Ok(Json(json!({
+        "ok": true,
+        "id": report_id,
+        "whitelist": { "added": added, "removed": removed },
+    })))
+}
+
+/// Domains of report `report_id` that the next refresh adds to / removes from
+/// the `whitelist` view. Only these domains can change, so `whitelisted_domains`,
+/// which the view is built from, is evaluated for them with and without the report.
+async fn whitelist_delta(tx: &mut PgConnection, report_id: i32) -> Result<(i64, i64), sqlx::Error> {
+    sqlx::query_as(
+        "WITH affected AS (SELECT COALESCE(array_agg(domain), '{}') AS domains
+                           FROM report_row
+                           WHERE report_id = $1),
+             before AS (SELECT domain FROM whitelisted_domains((SELECT domains FROM affected), $1)),
+             after AS (SELECT domain FROM whitelisted_domains((SELECT domains FROM affected)))
+        SELECT (SELECT COUNT(*)
+                FROM after a
+                WHERE NOT EXISTS (SELECT 1 FROM before b WHERE b.domain = a.domain)) AS added,
+               (SELECT COUNT(*)
+                FROM before b
+                WHERE NOT EXISTS (SELECT 1 FROM after a WHERE a.domain = b.domain)) AS removed",
+    )
+    .bind(report_id)
+    .fetch_one(&mut *tx)
+    .await
+}
+
+/// Id of an already stored chunk of run `run_id` containing `domain`.
+/// A run is uploaded in chunks with disjoint domains, so any domain of a retried
+/// chunk identifies it. Holds a per-run lock until the transaction ends, so
+/// concurrent retries of the same chunk can't both get through.
+async fn uploaded_chunk(
+    tx: &mut PgConnection,
+    run_id: Uuid,
+    domain: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1::text))")
+        .bind(run_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query_scalar(
+        "SELECT r.id
+        FROM reports r
+                 JOIN report_row rr ON rr.report_id = r.id
+        WHERE r.run_id = $1
+          AND rr.domain = $2
+        LIMIT 1",
+    )
+    .bind(run_id)
+    .bind(domain)
+    .fetch_optional(&mut *tx)
+    .await
+}
 
-    Ok(Json(json!({ "ok": true, "id": report_id })))
+/// Rejects reports from misconfigured reporters before they reach the whitelist.
+fn validate_report(report: &AgencyReport) -> Result<(), String> {
+    let config = &report.config;
+    let checks = [
+        ("probe_count", config.probe_count > 0),
+        ("probe_ip_count", config.probe_ip_count > 0),
+        ("timeout_secs", (1..=MAX_TIMEOUT_SECS).contains(&config.timeout_secs)),
+        ("retry_count", config.retry_count <= MAX_RETRY_COUNT),
+        ("path", !config.path.trim().is_empty()),
+        ("ip", !(config.ip.is_unspecified() || config.ip.is_loopback() || config.ip.is_multicast())),
+    ];
+    if let Some((field, _)) = checks.iter().find(|(_, valid)| !valid) {
+        return Err(format!("implausible config.{field}"));
+    }
+    if report.data.len() > MAX_REPORT_ROWS {
+        return Err(format!("data has {} rows, at most {MAX_REPORT_ROWS} are accepted", report.data.len()));
+    }
+    Ok(())
+}
+
+/// Decompresses a zstd body, refusing to inflate it past `limit` bytes.
+fn decompress_zstd(bytes: &[u8], limit: u64) -> Result<Vec<u8>, (Status, String)> {
+    let decoder = zstd::stream::read::Decoder::new(bytes)
+        .map_err(|e| (Status::BadRequest, format!("malformed zstd body: {e}")))?;
+    let mut decompressed = vec![];
+    decoder.take(limit + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| (Status::BadRequest, format!("malformed zstd body: {e}")))?;
+    if decompressed.len() as u64 > limit {
+        return Err((Status::PayloadTooLarge, "report is too large".to_string()));
+    }
+    Ok(decompressed)
+}
+
+/// Checks the schema before decoding, so reports from newer reporters get a clear error.
+fn decode_report(bytes: &[u8]) -> Result<AgencyReport, (Status, String)> {
+    let SchemaPeek(schema) = msgpack::from_slice(bytes)
+        .map_err(|e| (Status::BadRequest, format!("malformed report: {e}")))?;
+    if !(1..=SCHEMA).contains(&schema) {
+        return Err((
+            Status::BadRequest,
+            format!("unsupported report schema {schema}, this server accepts up to {SCHEMA}"),
+        ));
+    }
+    msgpack::from_slice(bytes).map_err(|e| (Status::BadRequest, format!("malformed report: {e}")))
+}
+
+/// Label of the `evidence` database enum, which spells connection errors differently.
+fn evidence_label(evidence: &Evidence) -> String {
+    match evidence {
+        Evidence::ConnectError => "connection_error".to_string(),
+        evidence => evidence.to_string(),
+    }
 }
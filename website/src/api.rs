@@ -0,0 +1,123 @@
+use log::error;
+use querying::geoip::IpInfo;
+use querying::lists::{DomainMatch, NetworkRecord};
+use querying::resolver::ResolveError;
+use querying::target::Target;
+use querying::{Check, CheckError, CheckVerdict, Checker};
+use crate::ratelimit::RateLimited;
+use crate::{ErrorCode, JsonError};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::RwLock;
+use rocket::State;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Clear,
+    Blocked,
+}
+
+#[derive(Serialize)]
+pub struct BlockedSubnet {
+    subnet: String,
+    prefix_len: u8,
+}
+
+#[derive(Serialize)]
+pub struct CheckResponse {
+    target: String,
+    target_type: &'static str,
+    verdict: Verdict,
+    /// Reason for the verdict in English, see [`Check::explain`]
+    explanation: String,
+    /// Blocked domain from the RKN list, possibly a parent of `target`
+    rkn_domain: Option<String>,
+    parent_domain: bool,
+    blocked_subnets: Vec<BlockedSubnet>,
+    providers: HashMap<String, HashSet<NetworkRecord>>,
+    ips: Vec<IpAddr>,
+    cname_chain: Vec<String>,
+    geo: IpInfo,
+}
+
+/// JSON counterpart of the `/check` page. Unresolvable targets are 404s.
+#[get("/check?<target>")]
+pub async fn check(
+    target: &str,
+    checker: &State<Arc<RwLock<Checker>>>,
+    _limit: RateLimited,
+) -> Result<Json<CheckResponse>, (Status, Json<JsonError>)> {
+    let target = Target::from(target);
+    let check = match checker.read().await.check(target.clone()).await {
+        Ok(check) => check,
+        Err(CheckError::NotFound) => return Err(JsonError::respond(ErrorCode::NotFound, "domain not found")),
+        Err(CheckError::InvalidTarget(e)) => return Err(JsonError::respond(ErrorCode::InvalidTarget, e.to_string())),
+        Err(CheckError::ResolveError(e @ ResolveError::Timeout)) => {
+            return Err(JsonError::respond(ErrorCode::UpstreamTimeout, e.to_string()));
+        }
+        Err(CheckError::ResolveError(e @ (ResolveError::ServFail | ResolveError::Refused))) => {
+            return Err(JsonError::respond(ErrorCode::ResolveFailed, e.to_string()));
+        }
+        Err(e) => {
+            error!("check failed {:?}", e);
+            return Err(JsonError::respond(ErrorCode::InternalError, "check failed"));
+        }
+    };
+
+    let explanation = check.explain("en");
+    let Check { verdict, geo, ips, cname_chain, rkn_subnets } = check;
+    let (verdict, rkn_domain, providers) = match verdict {
+        CheckVerdict::Clear => (Verdict::Clear, None, HashMap::new()),
+        CheckVerdict::Blocked { rkn_domain, cdn_provider_subnets } => (Verdict::Blocked, rkn_domain, cdn_provider_subnets),
+    };
+
+    Ok(Json(CheckResponse {
+        target: target.to_query(),
+        target_type: target.readable_type(),
+        verdict,
+        explanation,
+        parent_domain: matches!(rkn_domain, Some(DomainMatch::Parent(_))),
+        rkn_domain: rkn_domain.as_ref().map(|d| d.domain().to_string()),
+        blocked_subnets: rkn_subnets.iter()
+            .map(|net| BlockedSubnet { subnet: net.to_string(), prefix_len: net.prefix_len() })
+            .collect(),
+        providers,
+        ips,
+        cname_chain,
+        geo,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct AsnFootprint {
+    asn: String,
+    count: usize,
+    subnets: Vec<BlockedSubnet>,
+}
+
+/// RKN subnets attributed to an AS by GeoIP. `asn` is a number, optionally prefixed with `AS`.
+#[get("/asn/<asn>")]
+pub async fn asn(
+    asn: &str,
+    checker: &State<Arc<RwLock<Checker>>>,
+    _limit: RateLimited,
+) -> Result<Json<AsnFootprint>, (Status, Json<JsonError>)> {
+    let number = asn.strip_prefix("AS").or_else(|| asn.strip_prefix("as")).unwrap_or(asn);
+    let Ok(number) = number.parse::<u32>() else {
+        return Err(JsonError::respond(ErrorCode::BadRequest, format!("not an AS number: {asn}")));
+    };
+    let subnets = checker.read().await.asn_blocked_subnets(number).await;
+
+    Ok(Json(AsnFootprint {
+        asn: format!("AS{number}"),
+        count: subnets.len(),
+        subnets: subnets.iter()
+            .map(|net| BlockedSubnet { subnet: net.to_string(), prefix_len: net.prefix_len() })
+            .collect(),
+    }))
+}
@@ -34,11 +34,16 @@ pub async fn save_query(
                     .map(|n| n.cidr.to_string())
                     .collect(),
                 cdn_provider_subnets.keys().map(|p| p.to_string()).collect(),
-                rkn_domain.clone(),
+                rkn_domain.as_ref().map(|d| d.domain().to_string()),
             )
         } else {
             (vec![], vec![], None)
         };
+    // Disjoint answers from the upstreams usually mean one of them is being tampered with
+    let dns_divergence = match target {
+        Target::Domain(domain) => checker.dns_divergence(domain, &check.ips).await,
+        _ => None,
+    };
 
     let id = sqlx::query_scalar(
         "INSERT INTO queries (
@@ -52,8 +57,9 @@ pub async fn save_query(
                      resolved_ips,
                      cdn_networks,
                      cdn_providers,
-                     rkn_domain
-                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING id",
+                     rkn_domain,
+                     dns_divergence
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id",
     )
     .bind(target.to_query())
     .bind(addr.ip.to_string())
@@ -79,6 +85,7 @@ pub async fn save_query(
     .bind(cdn_networks)
     .bind(cdn_providers)
     .bind(rkn_domain)
+    .bind(dns_divergence)
     .fetch_one(&mut ***db)
     .await?;
 
@@ -116,6 +123,71 @@ impl<'r> FromRequest<'r> for Agency {
     }
 }
 
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct FeedbackStats {
+    pub works: i64,
+    pub broken: i64,
+}
+
+/// User feedback on every saved check of the same target as query `id`.
+pub async fn feedback_stats(
+    db: &mut Connection<Db>,
+    id: Uuid,
+) -> Result<FeedbackStats, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE h.works)     AS works,
+                COUNT(*) FILTER (WHERE NOT h.works) AS broken
+        FROM human_reports h
+                 JOIN queries q ON q.id = h.id
+        WHERE q.query = (SELECT query FROM queries WHERE id = $1)",
+    )
+    .bind(id)
+    .fetch_one(&mut ***db)
+    .await
+}
+
+/// A saved check as it was stored, without the client's address.
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct SavedCheck {
+    id: String,
+    pub query: String,
+    pub target_country_code: Option<String>,
+    target_asn: Option<String>,
+    pub target_provider: Option<String>,
+    resolved_ips: Option<Vec<String>>,
+    cdn_networks: Option<Vec<String>>,
+    cdn_providers: Option<Vec<String>>,
+    rkn_domain: Option<String>,
+    dns_divergence: Option<bool>,
+    pub blocked: bool,
+    date: Option<NaiveDateTime>,
+}
+
+pub async fn saved_check(
+    db: &mut Connection<Db>,
+    id: Uuid,
+) -> Result<Option<SavedCheck>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id::TEXT AS id,
+                query,
+                target_country_code,
+                target_asn,
+                target_provider,
+                resolved_ips,
+                cdn_networks,
+                cdn_providers,
+                rkn_domain,
+                dns_divergence,
+                (rkn_domain IS NOT NULL OR COALESCE(cardinality(cdn_providers), 0) > 0) AS blocked,
+                date
+        FROM queries
+        WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(&mut ***db)
+    .await
+}
+
 #[derive(Serialize, Debug, sqlx::FromRow)]
 pub struct WhitelistedEntry {
     domain: Option<String>,
@@ -153,11 +225,13 @@ pub struct WhitelistHistogramBin {
     pub count: Option<i64>,
 }
 
+/// Counts whitelisted domains in `bins` rank ranges of `bin_width` each,
+/// leaving out domains under `exclude_tld` (e.g. `co.uk`).
 pub async fn collect_histogram(
     db: &mut Connection<Db>,
     bins: i32,
-    limit: i32,
-    filter: bool,
+    bin_width: i32,
+    exclude_tld: Option<&str>,
 ) -> Result<Vec<WhitelistHistogramBin>, sqlx::Error> {
 
     sqlx::query_as!(
@@ -169,14 +243,50 @@ SELECT
   b.bin as bin_id,
   b.bin * $2 + 1    AS bin_min_rank,
   (b.bin + 1) * $2 AS bin_max_rank,
-  COUNT(case when not $3 or w.domain not like '%.co.uk' then 1 end)     AS count
+  COUNT(case when $3::text is null or right(w.domain, length($3::text) + 1) <> ('.' || $3::text) then 1 end) AS count
 FROM bins b
 LEFT JOIN whitelist w
   ON FLOOR(w.rank / $2) = b.bin
 GROUP BY b.bin
-ORDER BY b.bin;", bins, limit / bins, filter
+ORDER BY b.bin;", bins, bin_width, exclude_tld
     )
     .fetch_all(&mut ***db)
     .await
     .into()
 }
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TopBlocked {
+    pub domain: String,
+    pub checks: i64,
+    /// Share of `checks` that came out blocked
+    pub blocked_ratio: f64,
+}
+
+/// Most checked domains with at least one blocked check within the last `days`.
+/// Relies on `queries_date_idx` to limit the scan to the window.
+pub async fn top_blocked(
+    db: &mut Connection<Db>,
+    days: i32,
+    limit: i64,
+) -> Result<Vec<TopBlocked>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT query                                 AS domain,
+                COUNT(*)                              AS checks,
+                AVG(blocked::INT)::FLOAT8             AS blocked_ratio
+        FROM (SELECT query,
+                     (rkn_domain IS NOT NULL OR COALESCE(cardinality(cdn_providers), 0) > 0) AS blocked
+              FROM queries
+              WHERE date > NOW() - make_interval(days => $1)
+                AND query NOT LIKE '%:%'
+                AND query !~ '^[0-9.]+$') q
+        GROUP BY query
+        HAVING bool_or(blocked)
+        ORDER BY checks DESC, query
+        LIMIT $2",
+    )
+    .bind(days)
+    .bind(limit)
+    .fetch_all(&mut ***db)
+    .await
+}
@@ -0,0 +1,84 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::serde_json::{self, Value};
+use rocket::Request;
+use serde::Serialize;
+use std::sync::LazyLock;
+
+static RU: LazyLock<Value> = LazyLock::new(|| parse(include_str!("../locales/ru.json")));
+static EN: LazyLock<Value> = LazyLock::new(|| parse(include_str!("../locales/en.json")));
+
+fn parse(bundle: &str) -> Value {
+    serde_json::from_str(bundle).expect("invalid locale bundle")
+}
+
+/// UI language picked from `?lang=`, then `Accept-Language`, defaulting to Russian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    Ru,
+    En,
+}
+
+impl Lang {
+    fn parse(code: &str) -> Option<Lang> {
+        let primary = code.split(['-', '_']).next()?.trim();
+        if primary.eq_ignore_ascii_case("ru") {
+            Some(Lang::Ru)
+        } else if primary.eq_ignore_ascii_case("en") {
+            Some(Lang::En)
+        } else {
+            None
+        }
+    }
+
+    /// Most preferred supported language of an `Accept-Language` header.
+    fn from_accept_language(header: &str) -> Option<Lang> {
+        let mut ranges: Vec<(Lang, f32)> = header.split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let lang = Lang::parse(parts.next()?)?;
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                Some((lang, q))
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.first().map(|(lang, _)| *lang)
+    }
+
+    /// Same as the request guard, for catchers which can't run guards.
+    pub fn of(request: &Request<'_>) -> Lang {
+        request.query_value::<&str>("lang")
+            .and_then(|lang| lang.ok())
+            .and_then(Lang::parse)
+            .or_else(|| request.headers().get_one("Accept-Language").and_then(Lang::from_accept_language))
+            .unwrap_or(Lang::Ru)
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::Ru => "ru",
+            Lang::En => "en",
+        }
+    }
+
+    /// Strings of this language, keyed the same in every bundle.
+    pub fn bundle(self) -> &'static Value {
+        match self {
+            Lang::Ru => &RU,
+            Lang::En => &EN,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Lang {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Lang::of(request))
+    }
+}
@@ -1,30 +1,48 @@
 #[macro_use]
 extern crate rocket;
+mod admin;
 mod agency;
+mod api;
 mod db;
+mod i18n;
+mod metrics;
+mod og;
+mod ratelimit;
+mod singleflight;
+mod stats;
 mod whitelist;
 
-use crate::db::{check_whitelist, save_query};
+use crate::db::{check_whitelist, feedback_stats, save_query, saved_check, FeedbackStats, SavedCheck};
+use crate::i18n::Lang;
+use crate::og::{OgCard, OgMemo};
+use crate::ratelimit::{RateLimited, RateLimiter, RetryAfter};
+use crate::singleflight::{InFlightChecks, Saving};
 use log::error;
-use querying::resolver::Resolver;
-use querying::target::Target;
-use querying::{Check, CheckError, CheckVerdict, Checker};
+use querying::lists::DomainMatch;
+use querying::resolver::{ResolveError, Resolver};
+use querying::target::{Target, TargetInput};
+use querying::{Check, CheckError, CheckVerdict, Checker, Readiness};
 use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
-use rocket::http::Status;
+use rocket::http::{ContentType, Header, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome};
 use rocket::response::content::RawJavaScript;
+use rocket::response::stream::{Event, EventStream};
 use rocket::tokio::sync::RwLock;
-use rocket::tokio::time;
-use rocket::{fairing, tokio, Build, Request, Rocket, State};
+use rocket::tokio::select;
+use rocket::{fairing, Build, Request, Rocket, Shutdown, State};
 use rocket_cache_response::CacheResponse;
 use rocket_client_addr::ClientRealAddr;
 use rocket_db_pools::{Connection, Database};
 use rocket_dyn_templates::{context, Metadata, Template};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use rocket::serde::json::Json;
+use rocket::serde::json::{Json, Value};
+use sqlx::types::chrono::{DateTime, Utc};
 use sqlx::types::Uuid;
 
 #[derive(rocket_db_pools::Database)]
@@ -34,23 +52,28 @@ struct Db(sqlx::PgPool);
 #[derive(Serialize)]
 struct GlobalContext {
     version: &'static str,
+    lang: Lang,
+    /// Strings of the UI language bundle
+    t: &'static Value,
 }
 
 impl GlobalContext {
-    fn new() -> Self {
+    fn new(lang: Lang) -> Self {
         GlobalContext {
             version: env!("CARGO_PKG_VERSION"),
+            lang,
+            t: lang.bundle(),
         }
     }
 }
 
 #[get("/")]
-async fn index(checker: &State<Arc<RwLock<Checker>>>) -> Template {
+async fn index(checker: &State<Arc<RwLock<Checker>>>, lang: Lang) -> Template {
     let checker_ref = checker.read().await;
     Template::render(
         "index",
         context! {
-            global: GlobalContext::new(),
+            global: GlobalContext::new(lang),
             domain_count: format_number(checker_ref.total_domains().await),
             v4_count: format_number(checker_ref.total_v4s().await),
             last_update: checker_ref.last_update(),
@@ -58,8 +81,40 @@ async fn index(checker: &State<Arc<RwLock<Checker>>>) -> Template {
     )
 }
 
+#[derive(Serialize)]
+struct UpdateEvent {
+    last_update: Option<DateTime<Utc>>,
+    domain_count: String,
+    v4_count: String,
+}
+
+/// Pushes fresh index page counters every time the databases are refreshed.
+#[get("/events/updates")]
+async fn update_events(checker: &State<Arc<RwLock<Checker>>>, mut shutdown: Shutdown) -> EventStream![] {
+    let checker = checker.inner().clone();
+    let mut updates = checker.read().await.subscribe_updates();
+    EventStream! {
+        loop {
+            select! {
+                changed = updates.changed() => if changed.is_err() { break },
+                _ = &mut shutdown => break,
+            }
+            let last_update = *updates.borrow_and_update();
+            let event = {
+                let checker_ref = checker.read().await;
+                UpdateEvent {
+                    last_update,
+                    domain_count: format_number(checker_ref.total_domains().await),
+                    v4_count: format_number(checker_ref.total_v4s().await),
+                }
+            };
+            yield Event::json(&event).event("update");
+        }
+    }
+}
+
 #[get("/kb/<page>")]
-fn page(metadata: Metadata, page: &str) -> Option<Template> {
+fn page(metadata: Metadata, page: &str, lang: Lang) -> Option<Template> {
     let page = format!("pages/{}", page);
     if !metadata.contains_template(&page) {
         return None;
@@ -68,18 +123,40 @@ fn page(metadata: Metadata, page: &str) -> Option<Template> {
     Some(Template::render(
         page,
         context! {
-            global: GlobalContext::new(),
+            global: GlobalContext::new(lang),
         },
     ))
 }
 
+#[derive(Serialize)]
+struct Health {
+    #[serde(flatten)]
+    readiness: Readiness,
+    installed_at: HashMap<&'static str, Option<DateTime<Utc>>>,
+    /// Databases lagging more than two refresh intervals behind the freshest one
+    stale: Vec<&'static str>,
+}
+
+/// 200 only once every database has data installed.
 #[get("/healthcheck")]
-async fn healthcheck(checker: &State<Arc<RwLock<Checker>>>) -> (Status, String) {
-    if checker.read().await.last_update().is_some() {
-        (Status::Ok, "OK".to_string())
-    } else {
-        (Status::InternalServerError, "LOADING DATABASES".to_string())
+async fn healthcheck(checker: &State<Arc<RwLock<Checker>>>) -> (Status, Json<Health>) {
+    let checker_ref = checker.read().await;
+    let readiness = checker_ref.readiness().await;
+    let installed_at = checker_ref.databases_age().await;
+
+    let lag_secs = 2 * database_interval() as i64;
+    let newest = installed_at.values().flatten().max().copied();
+    let mut stale: Vec<&'static str> = installed_at.iter()
+        .filter(|(_, installed)| matches!((installed, newest), (Some(installed), Some(newest)) if (newest - *installed).num_seconds() > lag_secs))
+        .map(|(name, _)| *name)
+        .collect();
+    stale.sort();
+    if !stale.is_empty() {
+        warn!("Stale databases: {:?}", stale);
     }
+
+    let status = if readiness.is_ready() { Status::Ok } else { Status::InternalServerError };
+    (status, Json(Health { readiness, installed_at, stale }))
 }
 
 #[post("/feedback/<uuid>/<works>")]
@@ -94,28 +171,227 @@ async fn feedback(uuid: &str, works: bool, mut db: Connection<Db>, addr: &Client
     Ok(())
 }
 
-#[get("/check?<target>")]
-async fn check(
+/// Value of the `If-None-Match` request header.
+struct IfNoneMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfNoneMatch(request.headers().get_one("If-None-Match").map(str::to_string)))
+    }
+}
+
+/// Everything a `/check` needs to run and save a check, besides the database.
+struct CheckState<'r> {
+    checker: &'r Arc<RwLock<Checker>>,
+    in_flight: &'r InFlightChecks,
+    addr: &'r ClientRealAddr,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CheckState<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let checker = try_outcome!(request.guard::<&State<Arc<RwLock<Checker>>>>().await);
+        let in_flight = try_outcome!(request.guard::<&State<InFlightChecks>>().await);
+        let addr = try_outcome!(request.guard::<&ClientRealAddr>().await);
+        Outcome::Success(CheckState { checker: checker.inner(), in_flight: in_flight.inner(), addr })
+    }
+}
+
+#[derive(Responder)]
+enum CheckPage {
+    Fresh(Template),
+    /// Results for bare IPs, which only change with a database update
+    Cached(Template, Header<'static>, Header<'static>, Header<'static>),
+    #[response(status = 304)]
+    NotModified(()),
+}
+
+#[get("/check/<uuid>/feedback")]
+async fn check_feedback(uuid: &str, mut db: Connection<Db>) -> Result<Json<FeedbackStats>, Status> {
+    let id = Uuid::try_parse(uuid).map_err(|_| Status::BadRequest)?;
+    Ok(Json(feedback_stats(&mut db, id).await.map_err(|_| Status::InternalServerError)?))
+}
+
+#[derive(Serialize)]
+struct Visit {
+    id: Uuid,
+    feedback: FeedbackStats,
+}
+
+/// Logs a visit to a cached IP check page, which can't carry a query id of its own.
+#[post("/check/visit?<target>")]
+async fn check_visit(
     target: &str,
     checker: &State<Arc<RwLock<Checker>>>,
     addr: &ClientRealAddr,
     mut db: Connection<Db>,
+    _limit: RateLimited,
+) -> Result<Json<Visit>, Status> {
+    let input = TargetInput::from(target);
+    let target = input.target();
+    if let Target::Domain(_) = target {
+        return Err(Status::BadRequest);
+    }
+    let check = checker.read().await.check(target.clone()).await
+        .map_err(|_| Status::NotFound)?;
+    let id = save_query(&mut db, target, &check, addr, checker.read().await).await
+        .map_err(|e| {
+            warn!("Failed to save check: {:?}", e);
+            Status::InternalServerError
+        })?;
+    let feedback = feedback_stats(&mut db, id).await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(Visit { id, feedback }))
+}
+
+/// Stored result of a past check, as a permalink and for sharing with support.
+#[get("/check/<uuid>/export.json")]
+async fn check_export(uuid: &str, mut db: Connection<Db>) -> Result<Json<SavedCheck>, Status> {
+    let id = Uuid::try_parse(uuid).map_err(|_| Status::BadRequest)?;
+    saved_check(&mut db, id).await
+        .map_err(|_| Status::InternalServerError)?
+        .map(Json)
+        .ok_or(Status::NotFound)
+}
+
+/// Preview image of a past check for link embeds. Saved checks never change, so it's rendered
+/// once per language and cached for a day.
+#[get("/check/<uuid>/og.png")]
+async fn check_og(
+    uuid: &str,
+    mut db: Connection<Db>,
+    lang: Lang,
+    memo: &State<OgMemo>,
+) -> Result<CacheResponse<(ContentType, Vec<u8>)>, Status> {
+    let id = Uuid::try_parse(uuid).map_err(|_| Status::BadRequest)?;
+    let saved = saved_check(&mut db, id).await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+    let verdict = if saved.blocked { "blocked" } else { "clear" };
+    let card = OgCard {
+        target: &saved.query,
+        blocked: saved.blocked,
+        verdict: lang.bundle()["result"][verdict].as_str().unwrap_or(verdict),
+        country_code: saved.target_country_code.as_deref(),
+        provider: saved.target_provider.as_deref(),
+    };
+    let png = memo.get_or_render(id, lang, &card).map_err(|e| {
+        error!("Failed to render preview: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(CacheResponse::Public {
+        responder: (ContentType::PNG, png),
+        max_age: 86400,
+        must_revalidate: false,
+    })
+}
+
+/// `resolver` picks one of the configured upstreams by name; unknown names are a 400.
+#[get("/check?<target>&<resolver>")]
+async fn check(
+    target: &str,
+    resolver: Option<&str>,
+    state: CheckState<'_>,
+    db: Connection<Db>,
+    if_none_match: IfNoneMatch,
+    lang: Lang,
+    _limit: RateLimited,
+) -> Result<CheckPage, Status> {
+    if let Some(name) = resolver && state.checker.read().await.resolver(name).is_none() {
+        return Err(Status::BadRequest);
+    }
+    let input = TargetInput::from(target);
+    let target = input.target();
+    // DNS answers can change at any time, so only IP results are cacheable
+    let etag = match target {
+        Target::Domain(_) => None,
+        Target::Ipv4(_) | Target::Ipv6(_) => state.checker.read().await.last_update()
+            .map(|updated| format!("\"{}-{}\"", updated.timestamp(), lang.code())),
+    };
+    if etag.is_some() && etag == if_none_match.0 {
+        // The page logs the visit itself through /check/visit
+        return Ok(CheckPage::NotModified(()));
+    }
+
+    let page = render_check(&input, resolver, &state, db, lang).await?;
+    Ok(match etag {
+        None => CheckPage::Fresh(page),
+        Some(etag) => CheckPage::Cached(
+            page,
+            Header::new("Cache-Control", format!("public, max-age={}", database_interval())),
+            Header::new("ETag", etag),
+            Header::new("Vary", "Accept-Language"),
+        ),
+    })
+}
+
+async fn render_check(
+    input: &TargetInput,
+    resolver: Option<&str>,
+    state: &CheckState<'_>,
+    mut db: Connection<Db>,
+    lang: Lang,
 ) -> Result<Template, Status> {
-    let target = Target::from(target);
-    let check = checker.read().await.check(target.clone()).await;
-    let id = if let Ok(check) = &check {
-        match save_query(&mut db, &target, check, addr, checker.read().await).await {
-            Ok(id) => Some(id.to_string()),
-            Err(e) => {
-                warn!("Failed to save check: {:?}", e);
-                None
+    let target = input.target();
+    // IP pages are cached and shared between visitors, so they load the query id through /check/visit
+    let cached = !matches!(target, Target::Domain(_));
+    // Upstreams answer differently, so only checks through the default resolver are shared
+    let (check, saving) = match resolver {
+        Some(name) => {
+            let checker_ref = state.checker.read().await;
+            let check = match checker_ref.resolver(name) {
+                Some(resolver) => checker_ref.check_with_resolver(target.clone(), resolver).await,
+                None => checker_ref.check(target.clone()).await,
+            };
+            (Arc::new(check), None)
+        }
+        None => {
+            let (check, saving) = state.in_flight.check(state.checker, target.clone()).await;
+            (check, Some(saving))
+        }
+    };
+    // Joined checks were saved by the request that started them
+    let id = match saving {
+        _ if cached => None,
+        Some(Saving::Joined(saved)) => saved.await,
+        saving => {
+            let id = match check.as_ref() {
+                Ok(check) => save_query(&mut db, target, check, state.addr, state.checker.read().await).await
+                    .inspect_err(|e| warn!("Failed to save check: {:?}", e))
+                    .ok(),
+                Err(_) => None,
+            };
+            if let (Some(Saving::Save(tx)), Some(id)) = (saving, id) {
+                let _ = tx.send(id);
             }
+            id
         }
+    };
+    let feedback = match id {
+        Some(id) => feedback_stats(&mut db, id).await
+            .inspect_err(|e| warn!("Failed to load feedback: {:?}", e))
+            .ok(),
+        None => None,
+    };
+    let id = id.map(|id| id.to_string());
+
+    // Saved with the default names, localized only for display
+    let localized_geo = if lang != Lang::Ru && let Ok(check) = check.as_ref() && let Some(ip) = check.ips.first() {
+        state.checker.read().await.geo_ip_localized(*ip, lang.code()).await
+            .inspect_err(|e| warn!("Failed to localize location: {:?}", e))
+            .ok()
     } else {
         None
     };
 
-    let whitelist = if let Target::Domain(domain) = &target {
+    let explanation = check.as_ref().as_ref().ok().map(|check| check.explain(lang.code()));
+
+    let whitelist = if let Target::Domain(domain) = target {
         check_whitelist(domain, &mut db)
             .await
             .map_err(|_| Status::InternalServerError)?
@@ -123,12 +399,13 @@ async fn check(
         None
     };
 
-    match check {
+    match check.as_ref() {
         Err(CheckError::NotFound) => Ok(Template::render(
             "empty",
             context! {
-                global: GlobalContext::new(),
+                global: GlobalContext::new(lang),
                 target: target.to_query(),
+                input: input.differing_input(),
                 target_type: target.readable_type(),
             },
         )),
@@ -136,21 +413,27 @@ async fn check(
             verdict: CheckVerdict::Clear,
             geo,
             ips,
+            cname_chain,
             rkn_subnets,
         }) => Ok(Template::render(
             "result",
             context! {
                 id,
-                global: GlobalContext::new(),
+                feedback,
+                cached,
+                global: GlobalContext::new(lang),
                 found: false,
+                explanation,
                 target: target.to_query(),
+                input: input.differing_input(),
                 target_type: target.readable_type(),
                 blocked_subnets: rkn_subnets.iter()
                     .map(|n| n.to_string())
                     .collect::<Vec<_>>(),
                 whitelist,
                 ips,
-                geo,
+                cname_chain,
+                geo: localized_geo.as_ref().unwrap_or(geo),
             },
         )),
         Ok(Check {
@@ -162,22 +445,53 @@ async fn check(
             geo,
             rkn_subnets,
             ips,
+            cname_chain,
         }) => Ok(Template::render(
             "result",
             context! {
                 id,
-                global: GlobalContext::new(),
+                feedback,
+                cached,
+                global: GlobalContext::new(lang),
                 found: true,
-                domain: rkn_domain,
+                explanation,
+                domain: rkn_domain.as_ref().map(DomainMatch::domain),
+                parent_domain: matches!(rkn_domain, Some(DomainMatch::Parent(_))),
                 providers: cdn_provider_subnets,
                 blocked_subnets: rkn_subnets.iter()
                     .map(|n| n.to_string())
                     .collect::<Vec<_>>(),
                 target: target.to_query(),
+                input: input.differing_input(),
                 target_type: target.readable_type(),
                 whitelist,
                 ips,
-                geo,
+                cname_chain,
+                geo: localized_geo.as_ref().unwrap_or(geo),
+            },
+        )),
+        Err(CheckError::InvalidTarget(_)) => Ok(Template::render(
+            "empty",
+            context! {
+                global: GlobalContext::new(lang),
+                target: target.to_query(),
+                input: input.differing_input(),
+                target_type: target.readable_type(),
+                invalid_target: true,
+            },
+        )),
+        Err(CheckError::ResolveError(ResolveError::Timeout)) => Err(Status::GatewayTimeout),
+        Err(CheckError::ResolveError(e @ (ResolveError::ServFail | ResolveError::Refused))) => Ok(Template::render(
+            "empty",
+            context! {
+                global: GlobalContext::new(lang),
+                target: target.to_query(),
+                input: input.differing_input(),
+                target_type: target.readable_type(),
+                resolver_error: match e {
+                    ResolveError::Refused => "refused",
+                    _ => "servfail",
+                },
             },
         )),
         Err(e) => {
@@ -188,26 +502,93 @@ async fn check(
 }
 
 #[catch(default)]
-fn default(status: Status, _req: &Request) -> Template {
+fn default(status: Status, req: &Request) -> Template {
     Template::render(
         "error",
         context! {
-            global: GlobalContext::new(),
+            global: GlobalContext::new(Lang::of(req)),
             status: status.code,
             reason: status.reason_lossy(),
         },
     )
 }
 
+/// Stable machine-readable error, so API clients don't have to branch on HTTP codes.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    BadRequest,
+    InvalidTarget,
+    Unauthorized,
+    NotFound,
+    RateLimited,
+    ResolveFailed,
+    UpstreamTimeout,
+    InternalError,
+}
+
+impl ErrorCode {
+    fn status(self) -> Status {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::InvalidTarget => Status::BadRequest,
+            ErrorCode::Unauthorized => Status::Unauthorized,
+            ErrorCode::NotFound => Status::NotFound,
+            ErrorCode::RateLimited => Status::TooManyRequests,
+            ErrorCode::ResolveFailed => Status::BadGateway,
+            ErrorCode::UpstreamTimeout => Status::GatewayTimeout,
+            ErrorCode::InternalError => Status::InternalServerError,
+        }
+    }
+
+    fn from_status(status: Status) -> ErrorCode {
+        match status.code {
+            400 | 422 => ErrorCode::BadRequest,
+            401 | 403 => ErrorCode::Unauthorized,
+            404 => ErrorCode::NotFound,
+            429 => ErrorCode::RateLimited,
+            502 => ErrorCode::ResolveFailed,
+            504 => ErrorCode::UpstreamTimeout,
+            _ => ErrorCode::InternalError,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct JsonError {
     code: u16,
+    error: ErrorCode,
     info: String,
 }
 
+impl JsonError {
+    fn respond(error: ErrorCode, info: impl Into<String>) -> (Status, Json<JsonError>) {
+        let status = error.status();
+        (status, Json(JsonError { code: status.code, error, info: info.into() }))
+    }
+}
+
 #[catch(default)]
 fn api_error(status: Status, _: &Request) -> Json<JsonError> {
-    Json(JsonError { code: status.code, info: status.reason_lossy().to_string() })
+    Json(JsonError {
+        code: status.code,
+        error: ErrorCode::from_status(status),
+        info: status.reason_lossy().to_string(),
+    })
+}
+
+#[derive(Responder)]
+struct TooManyRequests {
+    error: Json<JsonError>,
+    retry_after: Header<'static>,
+}
+
+/// Rate-limited clients get the JSON error shape even on HTML pages, along with when to retry.
+#[catch(429)]
+fn too_many_requests(status: Status, req: &Request) -> TooManyRequests {
+    TooManyRequests {
+        error: api_error(status, req),
+        retry_after: RetryAfter::header(req),
+    }
 }
 
 #[rocket::get("/lucide.js")]
@@ -247,6 +628,21 @@ fn format_number(number: usize) -> String {
         .join(" ")
 }
 
+/// Seconds between database refreshes, from `DATABASE_INTERVAL_SECONDS`.
+fn database_interval() -> u64 {
+    std::env::var("DATABASE_INTERVAL_SECONDS")
+        .unwrap_or("21600".to_string())
+        .parse()
+        .unwrap()
+}
+
+/// Upper bound of the random delay added to each refresh, from `DATABASE_JITTER_SECONDS`.
+fn database_jitter() -> u64 {
+    std::env::var("DATABASE_JITTER_SECONDS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300)
+}
+
 async fn run_migrations(rocket: Rocket<Build>) -> fairing::Result {
     match Db::fetch(&rocket) {
         Some(db) => match sqlx::migrate!("./migrations").run(&**db).await {
@@ -266,25 +662,8 @@ async fn rocket() -> _ {
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    let mut interval = time::interval(Duration::from_secs(
-        std::env::var("DATABASE_INTERVAL_SECONDS")
-            .unwrap_or("21600".to_string())
-            .parse()
-            .unwrap(),
-    ));
-
     let checker = Arc::new(RwLock::new(Checker::new().await));
-
-    let checker_clone = checker.clone();
-    tokio::spawn(async move {
-        info!("Refreshing DB every {:?}", interval.period());
-        loop {
-            interval.tick().await;
-            log::info!("Updating all DBs");
-            checker_clone.read().await.update_all().await;
-            log::info!("Updated databases");
-        }
-    });
+    checker.read().await.spawn_auto_update(Duration::from_secs(database_interval()), Duration::from_secs(database_jitter()));
 
     let figment = rocket::Config::figment().merge((
         "databases.cheburcheck.url",
@@ -294,14 +673,24 @@ async fn rocket() -> _ {
     rocket::custom(figment)
         .manage(Resolver::new().await)
         .manage(checker)
+        .manage(RateLimiter::from_env())
+        .manage(InFlightChecks::default())
+        .manage(OgMemo::default())
         .attach(Db::init())
         .attach(AdHoc::try_on_ignite("SQLx Migrations", run_migrations))
-        .mount("/", routes![index, check, healthcheck, page, feedback])
+        .attach(agency::WhitelistRefresh::fairing())
+        .mount("/", routes![index, check, check_visit, check_feedback, check_export, check_og, healthcheck, page, feedback, update_events, metrics::metrics])
         .mount("/vendor", routes![lucide, chartjs, chartjs_datalabels])
         .mount("/agency", routes![agency::upload_report])
-        .mount("/whitelist", routes![whitelist::histogram, whitelist::export_csv])
+        .mount("/api", routes![api::check, api::asn])
+        .mount("/admin", routes![admin::queries])
+        .mount("/whitelist", routes![whitelist::histogram, whitelist::export])
+        .mount("/stats", routes![stats::top_blocked_domains])
         .register("/agency", catchers![api_error])
-        .register("/", catchers![default])
+        .register("/api", catchers![api_error, too_many_requests])
+        .register("/admin", catchers![api_error])
+        .register("/stats", catchers![api_error])
+        .register("/", catchers![default, too_many_requests])
         .mount("/", FileServer::from(PathBuf::from("static")))
         .attach(Template::fairing())
 }
@@ -0,0 +1,44 @@
+use querying::{Checker, CheckerMetrics};
+use rocket::response::content::RawText;
+use rocket::tokio::sync::RwLock;
+use rocket::State;
+use std::fmt::Write;
+use std::sync::Arc;
+
+#[get("/metrics")]
+pub async fn metrics(checker: &State<Arc<RwLock<Checker>>>) -> RawText<String> {
+    RawText(render(&checker.read().await.metrics().await))
+}
+
+/// Prometheus text exposition format.
+fn render(m: &CheckerMetrics) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+
+    metric("cheburcheck_rkn_ips", "gauge", "Addresses covered by the RKN subnet list", &[
+        ("{family=\"v4\"}", m.rkn_v4.to_string()),
+        ("{family=\"v6\"}", m.rkn_v6.to_string()),
+    ]);
+    metric("cheburcheck_rkn_domains", "gauge", "Domains in the RKN list", &[
+        ("", m.rkn_domains.to_string()),
+    ]);
+    metric("cheburcheck_cdn_ips", "gauge", "Addresses covered by the CDN list", &[
+        ("{family=\"v4\"}", m.cdn_v4.to_string()),
+        ("{family=\"v6\"}", m.cdn_v6.to_string()),
+    ]);
+    if let Some(age) = m.last_update_age_secs {
+        metric("cheburcheck_last_update_age_seconds", "gauge", "Seconds since the databases were last updated", &[
+            ("", age.to_string()),
+        ]);
+    }
+    metric("cheburcheck_checks_total", "counter", "Checks performed since startup", &[
+        ("", m.checks_total.to_string()),
+    ]);
+    out
+}
@@ -0,0 +1,105 @@
+use crate::i18n::Lang;
+use resvg::{tiny_skia, usvg};
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 630;
+/// Targets longer than this are cut with an ellipsis to stay on the card.
+const MAX_TARGET_CHARS: usize = 36;
+/// Room for the target line, which shrinks from 72px to fit it.
+const TEXT_WIDTH: f32 = 1040.0;
+/// Rough advance of a bold DejaVu character, in ems.
+const CHAR_WIDTH: f32 = 0.65;
+/// Rendered cards kept by [`OgMemo`] before it starts over.
+const MEMO_CAPACITY: usize = 1024;
+
+/// Fonts are taken from the system, the image installs DejaVu for Latin and Cyrillic text.
+static FONTS: LazyLock<Arc<usvg::fontdb::Database>> = LazyLock::new(|| {
+    let mut fonts = usvg::fontdb::Database::new();
+    fonts.load_system_fonts();
+    Arc::new(fonts)
+});
+
+/// What the preview of a shared check shows.
+pub struct OgCard<'a> {
+    pub target: &'a str,
+    pub blocked: bool,
+    /// Localized verdict headline
+    pub verdict: &'a str,
+    pub country_code: Option<&'a str>,
+    pub provider: Option<&'a str>,
+}
+
+impl OgCard<'_> {
+    fn svg(&self) -> String {
+        let (accent, background) = if self.blocked { ("#ef4444", "#2a1214") } else { ("#22c55e", "#0f2417") };
+        let target = if self.target.chars().count() > MAX_TARGET_CHARS {
+            format!("{}…", self.target.chars().take(MAX_TARGET_CHARS - 1).collect::<String>())
+        } else {
+            self.target.to_string()
+        };
+        let target_size = (TEXT_WIDTH / (target.chars().count() as f32 * CHAR_WIDTH)).min(72.0);
+        let country = self.country_code.map(|code| format!(
+            r##"<rect x="80" y="440" width="120" height="80" rx="8" fill="#262626"/>
+            <text x="140" y="495" font-size="44" font-weight="bold" fill="white" text-anchor="middle">{}</text>"##,
+            escape(code)
+        )).unwrap_or_default();
+        let provider_x = if self.country_code.is_some() { 230 } else { 80 };
+        let provider = self.provider.map(|provider| format!(
+            r##"<text x="{provider_x}" y="495" font-size="36" fill="#a3a3a3">{}</text>"##,
+            escape(provider)
+        )).unwrap_or_default();
+
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" font-family="DejaVu Sans, sans-serif">
+            <rect width="100%" height="100%" fill="#171717"/>
+            <rect x="40" y="40" width="{}" height="{}" fill="{background}" stroke="{accent}" stroke-width="4"/>
+            <text x="80" y="150" font-size="40" fill="#a3a3a3">cheburcheck.ru</text>
+            <text x="80" y="270" font-size="{target_size:.0}" font-weight="bold" fill="white">{}</text>
+            <text x="80" y="370" font-size="64" font-weight="bold" fill="{accent}">{}</text>
+            {country}
+            {provider}
+            </svg>"##,
+            WIDTH - 80, HEIGHT - 80,
+            escape(&target),
+            escape(self.verdict),
+        )
+    }
+
+    pub fn render_png(&self) -> Result<Vec<u8>, String> {
+        let options = usvg::Options { fontdb: FONTS.clone(), ..Default::default() };
+        let tree = usvg::Tree::from_str(&self.svg(), &options).map_err(|e| e.to_string())?;
+        let mut pixmap = tiny_skia::Pixmap::new(WIDTH, HEIGHT).ok_or("empty pixmap")?;
+        resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+        pixmap.encode_png().map_err(|e| e.to_string())
+    }
+}
+
+/// Cards already rendered, by check and language. Saved checks never change,
+/// so a card only needs rasterizing once however often its link is shared.
+#[derive(Default)]
+pub struct OgMemo(Mutex<HashMap<(Uuid, Lang), Vec<u8>>>);
+
+impl OgMemo {
+    pub fn get_or_render(&self, id: Uuid, lang: Lang, card: &OgCard) -> Result<Vec<u8>, String> {
+        if let Some(png) = self.0.lock().unwrap().get(&(id, lang)) {
+            return Ok(png.clone());
+        }
+        let png = card.render_png()?;
+        let mut memo = self.0.lock().unwrap();
+        if memo.len() >= MEMO_CAPACITY {
+            memo.clear();
+        }
+        memo.insert((id, lang), png.clone());
+        Ok(png)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
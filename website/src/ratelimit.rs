@@ -0,0 +1,128 @@
+use crate::Db;
+use log::error;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use rocket_client_addr::ClientRealAddr;
+use rocket_db_pools::Connection;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stale buckets are only swept once this many clients are tracked.
+const SWEEP_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Who a bucket belongs to: anonymous callers share their IP's bucket,
+/// API clients get one of their own wherever they call from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    ApiClient(i32),
+}
+
+/// Per client token bucket holding up to a minute's worth of requests.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Reads the allowed requests per minute from `CHECK_RATE_LIMIT`, 0 disables the limit.
+    pub fn from_env() -> RateLimiter {
+        RateLimiter {
+            per_minute: std::env::var("CHECK_RATE_LIMIT").ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, or tells how long until the next one.
+    fn allow(&self, client: Client, per_minute: u32) -> Result<(), Duration> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = per_minute as f64;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= SWEEP_THRESHOLD {
+            buckets.retain(|_, b| now.duration_since(b.updated) < Duration::from_secs(60));
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: capacity, updated: now });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * capacity / 60.0;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / capacity));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Known programmatic caller from `api_clients`, identified by the `X-Api-Key` header.
+struct ApiClient {
+    id: i32,
+    per_minute: i32,
+}
+
+impl ApiClient {
+    async fn from_request(request: &Request<'_>) -> Option<ApiClient> {
+        let key = request.headers().get_one("X-Api-Key")?;
+        let mut db = request.guard::<Connection<Db>>().await.succeeded()?;
+        let client: Option<(i32, i32)> = sqlx::query_as("SELECT id, per_minute FROM api_clients WHERE token = $1")
+            .bind(key)
+            .fetch_optional(&mut **db)
+            .await
+            .inspect_err(|e| error!("Failed to look up API client: {}", e))
+            .ok()?;
+        client.map(|(id, per_minute)| ApiClient { id, per_minute })
+    }
+}
+
+/// Seconds until a throttled client may retry, for the 429 catcher.
+pub struct RetryAfter(u64);
+
+impl RetryAfter {
+    /// `Retry-After` for a request the guard turned away, a full minute if it didn't get to say.
+    pub fn header(request: &Request<'_>) -> Header<'static> {
+        let secs = request.local_cache(|| RetryAfter(60)).0;
+        Header::new("Retry-After", secs.to_string())
+    }
+}
+
+/// Guard for routes that hit the resolver, failing with 429 once the client's bucket is empty.
+/// Callers with a known API key use their own quota, everyone else the per-IP one.
+pub struct RateLimited;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimited {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(limiter) = request.rocket().state::<RateLimiter>() else {
+            return Outcome::Success(RateLimited);
+        };
+        let allowed = match ApiClient::from_request(request).await {
+            Some(client) => limiter.allow(Client::ApiClient(client.id), client.per_minute.max(0) as u32),
+            None => match request.guard::<&ClientRealAddr>().await.succeeded() {
+                Some(addr) => limiter.allow(Client::Ip(addr.ip), limiter.per_minute),
+                None => Ok(()),
+            },
+        };
+        match allowed {
+            Ok(()) => Outcome::Success(RateLimited),
+            Err(wait) => {
+                request.local_cache(|| RetryAfter(wait.as_secs_f64().ceil().max(1.0) as u64));
+                Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use querying::target::Target;
+use querying::{Check, CheckError, Checker};
+use rocket::futures::future::{BoxFuture, FutureExt, Shared};
+use rocket::tokio::sync::{oneshot, RwLock};
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub type SharedCheck = Arc<Result<Check, CheckError>>;
+
+/// Id the starting request saved the check under, `None` if it didn't.
+pub type SavedId = Shared<BoxFuture<'static, Option<Uuid>>>;
+
+struct InFlight {
+    check: Shared<BoxFuture<'static, SharedCheck>>,
+    saved: SavedId,
+}
+
+type InFlightMap = Arc<Mutex<HashMap<Target, InFlight>>>;
+
+/// Who saves a shared check.
+pub enum Saving {
+    /// This request started the check, the id goes to everyone who joined it
+    Save(oneshot::Sender<Uuid>),
+    /// Joined a running check, saved by the request that started it
+    Joined(SavedId),
+}
+
+/// Joins concurrent checks of the same target, so a link going viral
+/// costs one resolution instead of one per visitor.
+#[derive(Default)]
+pub struct InFlightChecks(InFlightMap);
+
+impl InFlightChecks {
+    /// Runs [`Checker::check`] or joins the one already running for `target`.
+    /// The caller that started it saves the result and passes the id on through [`Saving::Save`].
+    pub async fn check(&self, checker: &Arc<RwLock<Checker>>, target: Target) -> (SharedCheck, Saving) {
+        let (check, saving) = {
+            let mut in_flight = self.0.lock().unwrap();
+            match in_flight.get(&target) {
+                Some(running) => (running.check.clone(), Saving::Joined(running.saved.clone())),
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    let check = Self::spawn(self.0.clone(), checker.clone(), target.clone());
+                    let saved = rx.map(Result::ok).boxed().shared();
+                    in_flight.insert(target, InFlight { check: check.clone(), saved });
+                    (check, Saving::Save(tx))
+                }
+            }
+        };
+        (check.await, saving)
+    }
+
+    fn spawn(in_flight: InFlightMap, checker: Arc<RwLock<Checker>>, target: Target) -> Shared<BoxFuture<'static, SharedCheck>> {
+        async move {
+            let check = Arc::new(checker.read().await.check(target.clone()).await);
+            in_flight.lock().unwrap().remove(&target);
+            check
+        }.boxed().shared()
+    }
+}
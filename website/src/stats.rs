@@ -0,0 +1,25 @@
+use crate::db::{top_blocked, TopBlocked};
+use crate::Db;
+use log::error;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+
+const TOP_BLOCKED_LIMIT: i64 = 50;
+const MAX_DAYS: i32 = 90;
+
+/// Most checked domains that came out blocked over the last `days` (7 by default, at most 90).
+#[get("/top-blocked?<days>")]
+pub async fn top_blocked_domains(
+    mut db: Connection<Db>,
+    days: Option<i32>,
+) -> Result<Json<Vec<TopBlocked>>, Status> {
+    let days = days.unwrap_or(7).clamp(1, MAX_DAYS);
+    top_blocked(&mut db, days, TOP_BLOCKED_LIMIT)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Failed to collect top blocked domains: {:?}", e);
+            Status::InternalServerError
+        })
+}
@@ -5,12 +5,14 @@ use rocket::request::FromParam;
 use rocket_cache_response::CacheResponse;
 use rocket_db_pools::Connection;
 use std::io;
-use rocket::serde::json::Json;
-use crate::db::{collect_histogram, WhitelistHistogramBin};
+use rocket::serde::json::{serde_json, Json};
+use crate::db::{collect_histogram, WhitelistHistogramBin, WhitelistedEntry};
+use serde::Serialize;
 
 enum ExportType {
     Full,
     Domains,
+    Json,
 }
 
 impl<'r> FromParam<'r> for ExportType {
@@ -20,27 +22,52 @@ impl<'r> FromParam<'r> for ExportType {
         match param {
             "full.csv" => Ok(ExportType::Full),
             "domains.csv" => Ok(ExportType::Domains),
+            "export.json" => Ok(ExportType::Json),
             _ => Err(param),
         }
     }
 }
 
-#[get("/<export_type>")]
-pub async fn export_csv(
+/// Whitelist export, optionally limited to domains ranked within `min_rank..=max_rank`.
+#[get("/<export_type>?<min_rank>&<max_rank>")]
+pub async fn export(
     export_type: ExportType,
+    min_rank: Option<i32>,
+    max_rank: Option<i32>,
     mut db: Connection<Db>,
 ) -> Result<CacheResponse<(ContentType, Vec<u8>)>, io::Error> {
+    // plain integers, so safe to inline where COPY can't take bind parameters
+    let filter = match (min_rank, max_rank) {
+        (None, None) => String::new(),
+        (min, max) => format!(
+            "WHERE rank BETWEEN {} AND {}",
+            min.unwrap_or(i32::MIN),
+            max.unwrap_or(i32::MAX)
+        ),
+    };
     let query = match export_type {
-        ExportType::Full => {
-            "COPY (SELECT domain, rank, last_ok FROM whitelist) TO STDOUT WITH (FORMAT CSV, HEADER, ENCODING 'UTF8')"
-        }
-        ExportType::Domains => {
-            "COPY (SELECT domain FROM whitelist) TO STDOUT WITH (FORMAT CSV, ENCODING 'UTF8')"
+        ExportType::Full => format!(
+            "COPY (SELECT domain, rank, last_ok FROM whitelist {filter}) TO STDOUT WITH (FORMAT CSV, HEADER, ENCODING 'UTF8')"
+        ),
+        ExportType::Domains => format!(
+            "COPY (SELECT domain FROM whitelist {filter}) TO STDOUT WITH (FORMAT CSV, ENCODING 'UTF8')"
+        ),
+        ExportType::Json => {
+            let entries: Vec<WhitelistedEntry> =
+                sqlx::query_as(&format!("SELECT domain, rank, last_ok FROM whitelist {filter} ORDER BY rank"))
+                    .fetch_all(&mut **db)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            return Ok(CacheResponse::Public {
+                responder: (ContentType::JSON, serde_json::to_vec(&entries)?),
+                max_age: 86400,
+                must_revalidate: false,
+            });
         }
     };
 
     let mut stream = db
-        .copy_out_raw(query)
+        .copy_out_raw(&query)
         .await
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
@@ -58,9 +85,27 @@ pub async fn export_csv(
     })
 }
 
-#[get("/histogram?<filter>&<limit>")]
-pub async fn histogram(mut db: Connection<Db>, filter: Option<bool>, limit: Option<i32>) -> Result<Json<Vec<WhitelistHistogramBin>>, Status> {
+#[derive(Serialize)]
+pub struct WhitelistHistogram {
+    /// Ranks covered by each bin
+    bin_width: i32,
+    bins: Vec<WhitelistHistogramBin>,
+}
+
+#[get("/histogram?<limit>&<bins>&<exclude_tld>")]
+pub async fn histogram(
+    mut db: Connection<Db>,
+    limit: Option<i32>,
+    bins: Option<i32>,
+    exclude_tld: Option<&str>,
+) -> Result<Json<WhitelistHistogram>, Status> {
     let limit = limit.unwrap_or(100_000).clamp(0, 1_000_000);
-    Ok(Json(collect_histogram(&mut db, 50, limit, filter.is_some()).await
-        .map_err(|e| Status::InternalServerError)?))
+    let bins = bins.unwrap_or(50).clamp(1, 200);
+    let bin_width = (limit / bins).max(1);
+    let exclude_tld = exclude_tld.map(|tld| tld.trim_start_matches('.')).filter(|tld| !tld.is_empty());
+    Ok(Json(WhitelistHistogram {
+        bin_width,
+        bins: collect_histogram(&mut db, bins, bin_width, exclude_tld).await
+            .map_err(|_| Status::InternalServerError)?,
+    }))
 }